@@ -0,0 +1,94 @@
+//! Discovery of the ACPI root system description pointer (RSDP).
+
+use core::ffi::c_void;
+
+use uefi::table::cfg::{ConfigTableEntry, ACPI2_GUID, ACPI_GUID};
+
+/// Signature every RSDP starts with, on a 16 byte boundary
+#[cfg(target_arch = "x86_64")]
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Size of the ACPI 1.0 RSDP, the part covered by its checksum
+#[cfg(target_arch = "x86_64")]
+const RSDP_V1_LEN: usize = 20;
+
+/// Real mode segment of the EBDA is stored here in the BIOS data area
+#[cfg(target_arch = "x86_64")]
+const EBDA_SEGMENT_PTR: usize = 0x40E;
+
+/// Only the first KiB of the EBDA is searched
+#[cfg(target_arch = "x86_64")]
+const EBDA_SEARCH_LEN: usize = 1024;
+
+/// Read only BIOS area searched after the EBDA
+#[cfg(target_arch = "x86_64")]
+const BIOS_AREA: (usize, usize) = (0xE0000, 0x100000);
+
+/// Find the RSDP in the UEFI configuration table, preferring the ACPI 2.0+ entry over ACPI 1.0
+pub fn find_rsdp(config_table: &[ConfigTableEntry]) -> Option<*const c_void> {
+    if let Some(entry) = config_table.iter().find(|e| e.guid == ACPI2_GUID) {
+        info!("Found ACPI 2.0 RSDP @ {:#?}", entry.address);
+        return Some(entry.address);
+    }
+
+    if let Some(entry) = config_table.iter().find(|e| e.guid == ACPI_GUID) {
+        info!("Found ACPI 1.0 RSDP @ {:#?}", entry.address);
+        return Some(entry.address);
+    }
+
+    warn!("No ACPI RSDP found in the UEFI configuration table");
+    None
+}
+
+/// Search the first KiB of the EBDA and then 0xE0000 - 0xFFFFF for an RSDP with a valid
+/// checksum, the way a BIOS OS would. Only safe while firmware identity maps low memory.
+#[cfg(target_arch = "x86_64")]
+pub fn scan_legacy_rsdp() -> Option<*const c_void> {
+    let ebda = unsafe { core::ptr::read_volatile(EBDA_SEGMENT_PTR as *const u16) } as usize * 16;
+    // a zero segment means there is no EBDA, rather than one at address 0
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, ebda + EBDA_SEARCH_LEN) {
+            info!(
+                "Found ACPI RSDP @ {:#X} by scanning the EBDA",
+                rsdp as usize
+            );
+            return Some(rsdp);
+        }
+    }
+
+    if let Some(rsdp) = scan_rsdp(BIOS_AREA.0, BIOS_AREA.1) {
+        info!(
+            "Found ACPI RSDP @ {:#X} by scanning the BIOS area",
+            rsdp as usize
+        );
+        return Some(rsdp);
+    }
+
+    warn!("No ACPI RSDP found in the EBDA or BIOS area either");
+    None
+}
+
+/// There is no legacy BIOS area to scan on this architecture
+#[cfg(not(target_arch = "x86_64"))]
+pub fn scan_legacy_rsdp() -> Option<*const c_void> {
+    warn!("acpi_scan is only supported on x86_64");
+    None
+}
+
+/// Find the first RSDP between `start` and `end`, checking each 16 byte boundary
+#[cfg(target_arch = "x86_64")]
+fn scan_rsdp(start: usize, end: usize) -> Option<*const c_void> {
+    (start..end - RSDP_V1_LEN)
+        .step_by(16)
+        .find(|&addr| {
+            let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, RSDP_V1_LEN) };
+            bytes.starts_with(RSDP_SIGNATURE) && checksum_ok(bytes)
+        })
+        .map(|addr| addr as *const c_void)
+}
+
+/// ACPI checksums make all the bytes of a structure sum to zero
+#[cfg(target_arch = "x86_64")]
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
@@ -0,0 +1,91 @@
+//! Reading a kernel packed at a fixed location on a block device, bypassing the filesystem.
+//!
+//! With `block_lba` and `block_count` set in a volume's config, `block_count` blocks starting at
+//! `block_lba` are read through the BlockIO protocol on that volume's own handle, so the LBA is
+//! relative to the start of the partition, not the disk.
+
+use alloc::vec::Vec;
+
+use goblin::elf::header::ELFMAG;
+use uefi::prelude::*;
+use uefi::proto::media::block::BlockIO;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The volume has no usable BlockIO protocol
+    Open(Status),
+    /// There is no medium in the device
+    NoMedia,
+    /// The requested blocks extend past the last block of the device
+    OutOfRange { last_block: u64 },
+    /// Firmware reported an error while reading
+    Read(Status),
+    /// The first block doesn't start with the ELF magic
+    NotElf,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Open(status) => write!(f, "unable to open BlockIO: {:?}", status),
+            Error::NoMedia => write!(f, "no media present"),
+            Error::OutOfRange { last_block } => {
+                write!(f, "blocks extend past the last block {}", last_block)
+            }
+            Error::Read(status) => write!(f, "read failed: {:?}", status),
+            Error::NotElf => write!(f, "data doesn't start with the ELF magic"),
+        }
+    }
+}
+
+/// Read `count` blocks starting at `lba` from the BlockIO on `device`
+pub fn read_kernel(
+    bt: &BootServices,
+    device: Handle,
+    agent: Handle,
+    lba: u64,
+    count: u64,
+) -> Result<Vec<u8>, Error> {
+    let params = OpenProtocolParams {
+        handle: device,
+        agent,
+        controller: None,
+    };
+    let scoped: ScopedProtocol<BlockIO> = bt
+        .open_protocol(params, OpenProtocolAttributes::GetProtocol)
+        .map_err(|e| Error::Open(e.status()))?
+        .log();
+    let block_io = unsafe { &*scoped.interface.get() };
+
+    let media = block_io.media();
+    if !media.is_media_preset() {
+        return Err(Error::NoMedia);
+    }
+    let last_block = media.last_block();
+    if count == 0
+        || lba
+            .checked_add(count - 1)
+            .map_or(true, |end| end > last_block)
+    {
+        return Err(Error::OutOfRange { last_block });
+    }
+
+    // the buffer comes from AllocatePool and is only guaranteed to be 8 byte aligned
+    if media.io_align() > 8 {
+        warn!(
+            "Block device wants {} byte aligned buffers, the read may fail",
+            media.io_align()
+        );
+    }
+    let mut buf = crate::create_vec_buf(count as usize * media.block_size() as usize);
+    block_io
+        .read_blocks(media.media_id(), lba, &mut buf)
+        .map_err(|e| Error::Read(e.status()))?
+        .log();
+
+    if !buf.starts_with(ELFMAG) {
+        return Err(Error::NotElf);
+    }
+    Ok(buf)
+}
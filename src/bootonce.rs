@@ -0,0 +1,79 @@
+//! One-shot kernel override stored in a UEFI variable.
+//!
+//! An OS can set `NewtBootOnce` under `NEWT_VENDOR` to the path of a kernel, as UTF-8 with an
+//! optional trailing NUL, to have it tried before the configured one on the next boot only. The
+//! loader deletes the variable once a kernel has been found, so a bad kernel isn't retried forever,
+//! and straight away if it can't be used as a path at all.
+
+use arrayvec::ArrayString;
+use uefi::prelude::*;
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::{CStr16, Guid};
+
+/// Vendor GUID the loader's own variables are stored under
+pub const NEWT_VENDOR: VariableVendor = VariableVendor(Guid::from_values(
+    0x6e657774,
+    0x8a0c,
+    0x4c1e,
+    0x9d2b,
+    0x5f3a_7c41_b0e6,
+));
+
+/// Name of the variable holding the one-shot kernel path
+const BOOT_ONCE_NAME: &str = "NewtBootOnce";
+
+/// Read the one-shot kernel path, None if it isn't set or isn't a usable path
+pub fn read(rt: &RuntimeServices) -> Option<ArrayString<64>> {
+    let mut name_buf = [0u16; 16];
+    let name = CStr16::from_str_with_buf(BOOT_ONCE_NAME, &mut name_buf).unwrap();
+
+    let mut buf = [0u8; 64];
+    let data = match rt.get_variable(name, &NEWT_VENDOR, &mut buf) {
+        Ok(v) => v.log().0,
+        Err(e) if e.status() == Status::NOT_FOUND => return None,
+        // too long for any path the loader can open, it would be rejected on every boot
+        Err(e) if e.status() == Status::BUFFER_TOO_SMALL => {
+            warn!(
+                "{} is longer than {} bytes, deleting it",
+                BOOT_ONCE_NAME,
+                buf.len()
+            );
+            clear(rt);
+            return None;
+        }
+        Err(e) => {
+            warn!("Unable to read {}: {:?}", BOOT_ONCE_NAME, e.status());
+            return None;
+        }
+    };
+
+    let path = match core::str::from_utf8(data) {
+        Ok(p) => p.trim_end_matches('\0').trim(),
+        Err(_) => {
+            warn!("{} is not valid UTF-8, deleting it", BOOT_ONCE_NAME);
+            clear(rt);
+            return None;
+        }
+    };
+    if path.is_empty() {
+        return None;
+    }
+
+    info!("{} is set, trying {} for this boot", BOOT_ONCE_NAME, path);
+    ArrayString::from(path).ok()
+}
+
+/// Delete the one-shot kernel path so the next boot goes back to the config
+pub fn clear(rt: &RuntimeServices) {
+    let mut name_buf = [0u16; 16];
+    let name = CStr16::from_str_with_buf(BOOT_ONCE_NAME, &mut name_buf).unwrap();
+
+    // writing no data deletes the variable
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS;
+    match rt.set_variable(name, &NEWT_VENDOR, attributes, &[]) {
+        Ok(_) => debug!("Cleared {}", BOOT_ONCE_NAME),
+        Err(e) => warn!("Unable to clear {}: {:?}", BOOT_ONCE_NAME, e.status()),
+    }
+}
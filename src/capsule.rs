@@ -0,0 +1,318 @@
+//! Firmware updates delivered as UEFI capsules, applied instead of booting the kernel.
+//!
+//! With `capsule` set in the config, the named file on the boot volume is checked for a valid
+//! `EFI_CAPSULE_HEADER`, handed to UpdateCapsule and the machine reset so firmware can apply it.
+//! `NewtCapsulePending` is set under `NEWT_VENDOR` before the reset. On the boot after, the loader
+//! deletes the capsule file and the variable instead of submitting it again, then boots as usual.
+
+use uefi::prelude::*;
+use uefi::proto::media::file::{Directory, File, FileAttribute, FileMode};
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::runtime::{ResetType, VariableAttributes};
+use uefi::{CStr16, Guid};
+
+use crate::bootonce::NEWT_VENDOR;
+use crate::FileError;
+
+/// Name of the variable marking a capsule as submitted
+const PENDING_NAME: &str = "NewtCapsulePending";
+
+/// The capsule is kept in memory across the reset and processed by firmware on the next boot
+const CAPSULE_FLAGS_PERSIST_ACROSS_RESET: u32 = 0x0001_0000;
+/// Only valid together with `CAPSULE_FLAGS_PERSIST_ACROSS_RESET`
+const CAPSULE_FLAGS_POPULATE_SYSTEM_TABLE: u32 = 0x0002_0000;
+/// Only valid together with `CAPSULE_FLAGS_PERSIST_ACROSS_RESET`
+const CAPSULE_FLAGS_INITIATE_RESET: u32 = 0x0004_0000;
+
+/// `EFI_CAPSULE_HEADER`, at the start of every capsule
+#[repr(C)]
+struct CapsuleHeader {
+    guid: Guid,
+    header_size: u32,
+    flags: u32,
+    image_size: u32,
+}
+
+/// `EFI_CAPSULE_BLOCK_DESCRIPTOR`, the scatter gather list entry for one contiguous block. A zero
+/// length with a zero address ends the list.
+#[repr(C)]
+struct BlockDescriptor {
+    length: u64,
+    address: u64,
+}
+
+/// The full `EFI_RUNTIME_SERVICES` table, the uefi crate's `RuntimeServices` stops at ResetSystem
+#[repr(C)]
+struct RawRuntimeServices {
+    _signature: u64,
+    _revision: u32,
+    /// Size of the whole table, tells how many services this firmware has
+    table_size: u32,
+    _crc32: u32,
+    _reserved: u32,
+    /// GetTime through ResetSystem, used through the uefi crate instead
+    _wrapped: [usize; 11],
+    update_capsule: unsafe extern "efiapi" fn(
+        headers: *const *const CapsuleHeader,
+        count: usize,
+        scatter_gather_list: u64,
+    ) -> Status,
+    query_capsule_capabilities: unsafe extern "efiapi" fn(
+        headers: *const *const CapsuleHeader,
+        count: usize,
+        max_size: *mut u64,
+        reset_type: *mut u32,
+    ) -> Status,
+}
+
+#[derive(Debug)]
+enum Error {
+    /// The capsule file exists but couldn't be read
+    File(FileError),
+    /// The file is smaller than a capsule header
+    TooSmall { len: usize },
+    /// The header's sizes don't match each other or the file
+    BadHeader {
+        header_size: u32,
+        image_size: u32,
+        len: usize,
+    },
+    /// A flag that requires `CAPSULE_FLAGS_PERSIST_ACROSS_RESET` is set without it
+    BadFlags { flags: u32 },
+    /// The runtime services table predates UpdateCapsule
+    Unsupported,
+    /// QueryCapsuleCapabilities rejected the capsule
+    Query(Status),
+    /// The capsule is larger than firmware accepts
+    TooLarge { len: usize, max_size: u64 },
+    /// No pages could be reserved to copy the capsule into
+    Alloc(Status),
+    /// UpdateCapsule failed
+    Update(Status),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::File(e) => write!(f, "{}", e),
+            Error::TooSmall { len } => write!(f, "is {} bytes, too small for a capsule", len),
+            Error::BadHeader {
+                header_size,
+                image_size,
+                len,
+            } => write!(
+                f,
+                "has an invalid header: header size {}, image size {}, file size {}",
+                header_size, image_size, len
+            ),
+            Error::BadFlags { flags } => write!(f, "has invalid flags {:#010X}", flags),
+            Error::Unsupported => write!(f, "can't be submitted, firmware has no UpdateCapsule"),
+            Error::Query(status) => write!(f, "was rejected by firmware: {:?}", status),
+            Error::TooLarge { len, max_size } => {
+                write!(f, "is {} bytes, firmware accepts at most {}", len, max_size)
+            }
+            Error::Alloc(status) => {
+                write!(f, "could not be copied to reserved pages: {:?}", status)
+            }
+            Error::Update(status) => write!(f, "could not be submitted: {:?}", status),
+        }
+    }
+}
+
+/// Submit the capsule at `path` on `root` and reset, or clean up after the capsule submitted on
+/// the last boot. Only returns if there is nothing to apply or submitting it failed, in which case
+/// the kernel should be booted as usual.
+pub fn apply(st: &SystemTable<Boot>, root: &mut Directory, path: &str) {
+    let rt = st.runtime_services();
+    if is_pending(rt) {
+        info!(
+            "Capsule {} was submitted on the last boot, removing it",
+            path
+        );
+        remove(root, path);
+        clear_pending(rt);
+        return;
+    }
+
+    let data = match crate::load_file(root, path) {
+        Ok(data) => data,
+        Err(FileError::NotFound) => {
+            debug!("No capsule {} on the boot volume", path);
+            return;
+        }
+        Err(e) => {
+            warn!("Capsule {} {}, booting normally", path, Error::File(e));
+            return;
+        }
+    };
+
+    match submit(st, &data) {
+        Ok(never) => match never {},
+        Err(e) => {
+            warn!("Capsule {} {}, booting normally", path, e);
+            clear_pending(rt);
+        }
+    }
+}
+
+/// Check the capsule header, hand the capsule to firmware and reset to apply it
+fn submit(st: &SystemTable<Boot>, data: &[u8]) -> Result<core::convert::Infallible, Error> {
+    let header = validate(data)?;
+
+    let raw = unsafe { &*(st.runtime_services() as *const _ as *const RawRuntimeServices) };
+    if (raw.table_size as usize) < core::mem::size_of::<RawRuntimeServices>() {
+        return Err(Error::Unsupported);
+    }
+
+    // firmware reads the capsule from physical memory, and with PERSIST_ACROSS_RESET after the
+    // reset too, so it goes in pages of its own followed by a two entry scatter gather list
+    let bs = st.boot_services();
+    let list_size = 2 * core::mem::size_of::<BlockDescriptor>();
+    // up to 7 bytes of padding go between the two
+    let size = data.len() + 7 + list_size;
+    let pages = (size + crate::PAGE_SIZE as usize - 1) / crate::PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .map_err(|e| Error::Alloc(e.status()))?
+        .log();
+    let capsule = base as *mut u8;
+    // the list follows the capsule, aligned up to its 8 byte fields
+    let list = ((base + data.len() as u64 + 7) & !7) as *mut BlockDescriptor;
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), capsule, data.len());
+        list.write(BlockDescriptor {
+            length: data.len() as u64,
+            address: base,
+        });
+        list.add(1).write(BlockDescriptor {
+            length: 0,
+            address: 0,
+        });
+    }
+    let headers = [capsule as *const CapsuleHeader];
+
+    let mut max_size = 0u64;
+    let mut reset_type = 0u32;
+    let status = unsafe {
+        (raw.query_capsule_capabilities)(headers.as_ptr(), 1, &mut max_size, &mut reset_type)
+    };
+    if status.is_error() {
+        return Err(Error::Query(status));
+    }
+    if data.len() as u64 > max_size {
+        return Err(Error::TooLarge {
+            len: data.len(),
+            max_size,
+        });
+    }
+
+    set_pending(st.runtime_services());
+    let status = unsafe { (raw.update_capsule)(headers.as_ptr(), 1, list as u64) };
+    if status.is_error() {
+        return Err(Error::Update(status));
+    }
+
+    let reset_type = match reset_type {
+        1 => ResetType::Warm,
+        2 => ResetType::Shutdown,
+        _ => ResetType::Cold,
+    };
+    info!(
+        "Submitted capsule {:?} ({} bytes, flags {:#010X}), resetting ({:?}) to apply it",
+        header.guid,
+        data.len(),
+        header.flags,
+        reset_type
+    );
+    st.runtime_services()
+        .reset(reset_type, Status::SUCCESS, None)
+}
+
+/// Check the sizes and flags in the header at the start of `data`
+fn validate(data: &[u8]) -> Result<&CapsuleHeader, Error> {
+    if data.len() < core::mem::size_of::<CapsuleHeader>() {
+        return Err(Error::TooSmall { len: data.len() });
+    }
+    // the buffer comes from AllocatePool, which is 8 byte aligned
+    let header = unsafe { &*(data.as_ptr() as *const CapsuleHeader) };
+
+    if (header.header_size as usize) < core::mem::size_of::<CapsuleHeader>()
+        || header.header_size > header.image_size
+        || header.image_size as usize != data.len()
+    {
+        return Err(Error::BadHeader {
+            header_size: header.header_size,
+            image_size: header.image_size,
+            len: data.len(),
+        });
+    }
+
+    let needs_persist = CAPSULE_FLAGS_POPULATE_SYSTEM_TABLE | CAPSULE_FLAGS_INITIATE_RESET;
+    if header.flags & needs_persist != 0 && header.flags & CAPSULE_FLAGS_PERSIST_ACROSS_RESET == 0 {
+        return Err(Error::BadFlags {
+            flags: header.flags,
+        });
+    }
+
+    Ok(header)
+}
+
+/// Delete the capsule file so it isn't submitted again
+fn remove(root: &mut Directory, path: &str) {
+    // deleting needs the file open for writing, open_path only opens read only
+    let file = crate::in_parent_dir(root, path, |dir, name| {
+        match dir.open(name, FileMode::ReadWrite, FileAttribute::empty()) {
+            Ok(f) => Some(f.log()),
+            Err(e) => {
+                warn!(
+                    "Unable to open capsule {} to remove it: {:?}",
+                    path,
+                    e.status()
+                );
+                None
+            }
+        }
+    });
+    let file = match file {
+        Some(f) => f,
+        None => return,
+    };
+    match file.delete() {
+        Ok(_) => debug!("Removed capsule {}", path),
+        Err(e) => warn!("Unable to remove capsule {}: {:?}", path, e.status()),
+    }
+}
+
+fn is_pending(rt: &RuntimeServices) -> bool {
+    let mut name_buf = [0u16; 24];
+    let name = CStr16::from_str_with_buf(PENDING_NAME, &mut name_buf).unwrap();
+    rt.get_variable_size(name, &NEWT_VENDOR).is_ok()
+}
+
+fn set_pending(rt: &RuntimeServices) {
+    let mut name_buf = [0u16; 24];
+    let name = CStr16::from_str_with_buf(PENDING_NAME, &mut name_buf).unwrap();
+
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS;
+    if let Err(e) = rt.set_variable(name, &NEWT_VENDOR, attributes, &[1]) {
+        warn!("Unable to set {}: {:?}", PENDING_NAME, e.status());
+    }
+}
+
+fn clear_pending(rt: &RuntimeServices) {
+    let mut name_buf = [0u16; 24];
+    let name = CStr16::from_str_with_buf(PENDING_NAME, &mut name_buf).unwrap();
+
+    // writing no data deletes the variable
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS;
+    match rt.set_variable(name, &NEWT_VENDOR, attributes, &[]) {
+        Ok(_) => debug!("Cleared {}", PENDING_NAME),
+        // NOT_FOUND just means nothing was pending
+        Err(e) if e.status() == Status::NOT_FOUND => (),
+        Err(e) => warn!("Unable to clear {}: {:?}", PENDING_NAME, e.status()),
+    }
+}
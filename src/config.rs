@@ -0,0 +1,598 @@
+//! Parsing for the boot configuration file stored on the ESP.
+//!
+//! The format is intentionally minimal so it can be handled without `std`: one `key = value`
+//! pair per line, with blank lines and lines starting with `#` ignored.
+//!
+//! The kernel is searched for on every volume in turn, starting with the volume the loader itself
+//! was started from and then the others in firmware order. On each one the `kernel` path from that
+//! volume's config is tried first, then `KERNEL` in the root and finally `\boot\KERNEL`. The
+//! config used is the one on the volume the kernel is found on. For a single boot a path can be
+//! put ahead of all of these with the `NewtBootOnce` UEFI variable, see `bootonce`.
+//!
+//! Builds that ship without a config can change the `KERNEL` looked up in the root by setting
+//! `NEWT_KERNEL_NAME` at build time, e.g. `NEWT_KERNEL_NAME=vmnewt make release`. A `kernel`
+//! in the config still takes precedence.
+//!
+//! `volume` is only read from the config on the volume the loader itself was started from. When
+//! set, just the volumes with that FAT label are searched, or every volume if none has it.
+//!
+//! The file name part of `kernel` may contain a single `*`, e.g. `kernel = KERNEL-*`, to boot the
+//! matching file with the highest version, so `KERNEL-5.10` is picked over `KERNEL-5.9`.
+//!
+//! Invalid values are logged and the key skipped, except for `modules`: a list that is too long
+//! or has a name over 64 bytes stops the boot, see `ConfigError`.
+//!
+//! ```text
+//! # newt.cfg
+//! volume = NEWT
+//! kernel = \boot\KERNEL
+//! block_lba = 2048
+//! block_count = 4096
+//! initrd = INITRD
+//! dtb = DTB
+//! modules = init.mod,console.mod
+//! cmdline = root=/dev/sda1 debug
+//! capsule = \EFI\UpdateCapsule\fw.cap
+//! timeout = 5
+//! stack_size = 65536
+//! read_retries = 3
+//! load = virtual
+//! load_base = 0x1000000
+//! kaslr = off
+//! runtime_virtual = false
+//! acpi_scan = false
+//! dump = elf
+//! log_level = info
+//! handoff = exit
+//! entry_abi = efi
+//! watchdog = off
+//! overlap_check = abort
+//! memtest = off
+//! progress = true
+//! progress_fg = 00AA00
+//! progress_bg = 000000
+//! ```
+
+use alloc::vec::Vec;
+
+use arrayvec::{ArrayString, ArrayVec};
+
+/// Name of the config file looked up in the root of each volume
+pub const CONFIG_FILE_NAME: &str = "newt.cfg";
+
+/// Kernel image name used when no config file is found or it doesn't specify one, taken from
+/// `NEWT_KERNEL_NAME` at build time if set
+pub const DEFAULT_KERNEL_NAME: &str = match option_env!("NEWT_KERNEL_NAME") {
+    Some(name) => name,
+    None => "KERNEL",
+};
+
+// the name has to fit in `BootConfig::kernel`
+const _: () = assert!(
+    !DEFAULT_KERNEL_NAME.is_empty() && DEFAULT_KERNEL_NAME.len() <= 64,
+    "NEWT_KERNEL_NAME must be 1 to 64 bytes"
+);
+
+/// Kernel stack size used when the config doesn't specify one
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+/// Times a failed read of the kernel is retried when the config doesn't specify it
+pub const DEFAULT_READ_RETRIES: usize = 3;
+
+/// Upper limit for `read_retries`, the stall between attempts doubles each time
+pub const MAX_READ_RETRIES: usize = 8;
+
+/// Paths tried on each volume when the configured kernel isn't found, in order
+pub const FALLBACK_KERNEL_PATHS: [&str; 2] = [DEFAULT_KERNEL_NAME, "\\boot\\KERNEL"];
+
+/// Initial ramdisk name used when the config doesn't specify one
+pub const DEFAULT_INITRD_NAME: &str = "INITRD";
+
+/// Devicetree blob name used when the config doesn't specify one
+pub const DEFAULT_DTB_NAME: &str = "DTB";
+
+/// Maximum number of boot modules that can be listed in `modules`
+pub const MAX_BOOT_MODULES: usize = 16;
+
+/// `load_base` has to leave the whole kernel below this, the top of the lower canonical half
+pub const MAX_LOAD_BASE: u64 = 0x0000_8000_0000_0000;
+
+/// Address space the kernel expects to be entered in
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LoadMode {
+    /// Segments are mapped at `p_vaddr` and the entry point is used as is
+    Virtual,
+    /// The kernel runs from `p_paddr`, e.g. with paging off, so the entry point is translated
+    Physical,
+}
+
+/// Diagnostic output printed while booting
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Dump {
+    /// ELF header, program headers and section headers of the kernel image, then halt
+    Elf,
+    /// Every descriptor of the final memory map, to serial right before the kernel is entered
+    Mmap,
+}
+
+/// State of the firmware when the kernel is entered
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Handoff {
+    /// The loader exits boot services and hands over the runtime system table
+    ExitBootServices,
+    /// Boot services are left running for the kernel to use and exit itself, on the firmware's
+    /// page tables, so the kernel has to be identity mapped
+    BootServices,
+}
+
+/// Registers the kernel entry point receives its arguments in on x86_64. AArch64 kernels are
+/// always entered with the table in X0, as AAPCS64 expects.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EntryAbi {
+    /// Microsoft x64, the table in RCX, as `extern "C"` means on UEFI targets
+    Efi,
+    /// System V, `EBOOT_MAGIC` in RAX and the table in RDI
+    SysV,
+}
+
+/// What to do with the firmware watchdog timer before entering the kernel
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Watchdog {
+    /// Disarm the watchdog so a slow kernel isn't reset after 5 minutes
+    Off,
+    /// Leave the watchdog as firmware armed it
+    Keep,
+}
+
+/// What to do when a kernel segment lands on memory the firmware doesn't report as free
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverlapCheck {
+    /// Log the overlap and try to load the segment anyway
+    Warn,
+    /// Refuse to load the kernel
+    Abort,
+}
+
+pub struct BootConfig {
+    /// Label of the volume to search for the kernel, compared case insensitively
+    pub volume: Option<ArrayString<32>>,
+    /// Path of the kernel image file, relative to the volume root
+    pub kernel: ArrayString<64>,
+    /// First block of a kernel read straight off this volume's block device instead of a file
+    pub block_lba: Option<u64>,
+    /// Number of blocks read from `block_lba`, the kernel is only read raw if both are set
+    pub block_count: Option<u64>,
+    /// Name of the initial ramdisk, loaded from the same volume as the kernel if present
+    pub initrd: ArrayString<64>,
+    /// Name of the flattened devicetree blob, loaded from the kernel's volume if present
+    pub dtb: ArrayString<64>,
+    /// Additional files loaded from the kernel's volume and described to it, in order
+    pub modules: ArrayVec<ArrayString<64>, MAX_BOOT_MODULES>,
+    /// Command line handed to the kernel verbatim
+    pub cmdline: Option<ArrayString<256>>,
+    /// Firmware capsule submitted instead of booting when it exists on the volume, see `capsule`
+    pub capsule: Option<ArrayString<64>>,
+    /// Seconds to wait for a key press that opens the boot menu, 0 boots immediately
+    pub timeout: usize,
+    /// Size in bytes of the stack the kernel is entered on, rounded up to whole pages
+    pub stack_size: usize,
+    /// Times a failed read of the kernel image is retried before giving up
+    pub read_retries: usize,
+    pub load: LoadMode,
+    /// Address position independent kernels are loaded at instead of the built in default
+    pub load_base: Option<u64>,
+    /// Slide position independent kernels a random distance above `load_base`
+    pub kaslr: bool,
+    /// Remap runtime services into the higher half with SetVirtualAddressMap before the handoff
+    pub runtime_virtual: bool,
+    /// Scan the EBDA and BIOS area for the RSDP if the config table has none, x86_64 only
+    pub acpi_scan: bool,
+    /// Print diagnostics and halt instead of jumping to the kernel
+    pub dump: Option<Dump>,
+    /// Most verbose level logged once the config has been read
+    pub log_level: log::LevelFilter,
+    pub handoff: Handoff,
+    pub entry_abi: EntryAbi,
+    pub watchdog: Watchdog,
+    pub overlap_check: OverlapCheck,
+    /// Test the pages reserved for the kernel with a few patterns before copying it into them
+    pub memtest: bool,
+    /// Clear the screen and draw a progress bar while loading
+    pub progress: bool,
+    /// Color of the progress bar as 0xRRGGBB
+    pub progress_fg: u32,
+    /// Color the screen is cleared to as 0xRRGGBB
+    pub progress_bg: u32,
+    /// Contents of the config file this was parsed from, None for the defaults
+    pub raw: Option<Vec<u8>>,
+    /// A problem with the config the boot can't continue past, unlike the keys that are skipped
+    pub error: Option<ConfigError>,
+}
+
+/// Config problems that stop the boot. A kernel started without a module it was told to expect
+/// fails somewhere far less obvious than the loader refusing to start it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `modules` lists more names than `MAX_BOOT_MODULES`
+    TooManyModules { count: usize },
+    /// A name in `modules` is longer than 64 bytes
+    ModuleNameTooLong { len: usize },
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::TooManyModules { count } => write!(
+                f,
+                "modules lists {} modules, at most {} are supported",
+                count, MAX_BOOT_MODULES
+            ),
+            ConfigError::ModuleNameTooLong { len } => write!(
+                f,
+                "a name in modules is {} bytes, at most 64 are supported",
+                len
+            ),
+        }
+    }
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            volume: None,
+            kernel: ArrayString::from(DEFAULT_KERNEL_NAME).unwrap(),
+            block_lba: None,
+            block_count: None,
+            initrd: ArrayString::from(DEFAULT_INITRD_NAME).unwrap(),
+            dtb: ArrayString::from(DEFAULT_DTB_NAME).unwrap(),
+            modules: ArrayVec::new(),
+            cmdline: None,
+            capsule: None,
+            timeout: 0,
+            stack_size: DEFAULT_STACK_SIZE,
+            read_retries: DEFAULT_READ_RETRIES,
+            load: LoadMode::Virtual,
+            load_base: None,
+            kaslr: false,
+            runtime_virtual: false,
+            acpi_scan: false,
+            dump: None,
+            log_level: log::LevelFilter::Info,
+            handoff: Handoff::ExitBootServices,
+            entry_abi: EntryAbi::Efi,
+            watchdog: Watchdog::Off,
+            overlap_check: OverlapCheck::Abort,
+            memtest: false,
+            progress: true,
+            progress_fg: 0x00AA00,
+            progress_bg: 0x000000,
+            raw: None,
+            error: None,
+        }
+    }
+}
+
+impl BootConfig {
+    /// Parse the contents of a config file, unknown keys and malformed lines are logged and skipped.
+    /// `raw` is left empty, the caller keeps the bytes if it wants them.
+    pub fn parse(data: &[u8]) -> BootConfig {
+        let mut config = BootConfig::default();
+
+        let text = match core::str::from_utf8(data) {
+            Ok(t) => t,
+            Err(_) => {
+                warn!("{} is not valid UTF-8, using defaults", CONFIG_FILE_NAME);
+                return config;
+            }
+        };
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => {
+                    warn!("ignoring malformed config line: {}", line);
+                    continue;
+                }
+            };
+
+            match key {
+                "volume" => match ArrayString::from(value) {
+                    Ok(label) => config.volume = Some(label),
+                    Err(_) => warn!("volume label '{}' is too long, ignoring", value),
+                },
+                "kernel" => match ArrayString::from(value) {
+                    Ok(name) => config.kernel = name,
+                    Err(_) => warn!("kernel name '{}' is too long, ignoring", value),
+                },
+                "block_lba" => match value.parse() {
+                    Ok(lba) => config.block_lba = Some(lba),
+                    Err(_) => warn!("invalid block_lba '{}', ignoring", value),
+                },
+                "block_count" => match value.parse() {
+                    Ok(count) if count > 0 => config.block_count = Some(count),
+                    _ => warn!("invalid block_count '{}', ignoring", value),
+                },
+                "initrd" => match ArrayString::from(value) {
+                    Ok(name) => config.initrd = name,
+                    Err(_) => warn!("initrd name '{}' is too long, ignoring", value),
+                },
+                "dtb" => match ArrayString::from(value) {
+                    Ok(name) => config.dtb = name,
+                    Err(_) => warn!("dtb name '{}' is too long, ignoring", value),
+                },
+                "modules" => match parse_modules(value) {
+                    Ok(modules) => config.modules = modules,
+                    Err(e) => config.error = config.error.or(Some(e)),
+                },
+                "cmdline" => match ArrayString::from(value) {
+                    Ok(cmdline) => config.cmdline = Some(cmdline),
+                    Err(_) => warn!("kernel command line is too long, ignoring"),
+                },
+                "capsule" => match ArrayString::from(value) {
+                    Ok(path) => config.capsule = Some(path),
+                    Err(_) => warn!("capsule path '{}' is too long, ignoring", value),
+                },
+                "timeout" => match value.parse() {
+                    Ok(secs) => config.timeout = secs,
+                    Err(_) => warn!("invalid timeout '{}', ignoring", value),
+                },
+                "stack_size" => match value.parse() {
+                    Ok(size) if size > 0 => config.stack_size = size,
+                    _ => warn!("invalid stack size '{}', ignoring", value),
+                },
+                "read_retries" => match value.parse() {
+                    Ok(retries) if retries <= MAX_READ_RETRIES => config.read_retries = retries,
+                    _ => warn!(
+                        "invalid read_retries '{}', expected 0 to {}",
+                        value, MAX_READ_RETRIES
+                    ),
+                },
+                "load" => match value {
+                    "virtual" => config.load = LoadMode::Virtual,
+                    "physical" => config.load = LoadMode::Physical,
+                    _ => warn!(
+                        "invalid load mode '{}', expected physical or virtual",
+                        value
+                    ),
+                },
+                "load_base" => match parse_address(value) {
+                    Some(base) if base % 4096 == 0 && base < MAX_LOAD_BASE => {
+                        config.load_base = Some(base)
+                    }
+                    _ => warn!(
+                        "invalid load_base '{}', expected a page aligned address below {:#X}",
+                        value, MAX_LOAD_BASE
+                    ),
+                },
+                "kaslr" => match parse_bool(value) {
+                    Some(b) => config.kaslr = b,
+                    None => warn!("invalid kaslr '{}', expected on or off", value),
+                },
+                "runtime_virtual" => match parse_bool(value) {
+                    Some(b) => config.runtime_virtual = b,
+                    None => warn!(
+                        "invalid runtime_virtual '{}', expected true or false",
+                        value
+                    ),
+                },
+                "acpi_scan" => match parse_bool(value) {
+                    Some(b) => config.acpi_scan = b,
+                    None => warn!("invalid acpi_scan '{}', expected true or false", value),
+                },
+                "dump" => match value {
+                    "elf" => config.dump = Some(Dump::Elf),
+                    "mmap" => config.dump = Some(Dump::Mmap),
+                    _ => warn!("invalid dump mode '{}', ignoring", value),
+                },
+                "log_level" => match value.parse() {
+                    Ok(level) => config.log_level = level,
+                    Err(_) => warn!("invalid log level '{}', ignoring", value),
+                },
+                "handoff" => match value {
+                    "exit" => config.handoff = Handoff::ExitBootServices,
+                    "boot-services" => config.handoff = Handoff::BootServices,
+                    _ => warn!(
+                        "invalid handoff '{}', expected exit or boot-services",
+                        value
+                    ),
+                },
+                "entry_abi" => match value {
+                    "efi" => config.entry_abi = EntryAbi::Efi,
+                    "sysv" => config.entry_abi = EntryAbi::SysV,
+                    _ => warn!("invalid entry_abi '{}', expected efi or sysv", value),
+                },
+                "watchdog" => match value {
+                    "off" => config.watchdog = Watchdog::Off,
+                    "keep" => config.watchdog = Watchdog::Keep,
+                    _ => warn!("invalid watchdog '{}', expected off or keep", value),
+                },
+                "overlap_check" => match value {
+                    "warn" => config.overlap_check = OverlapCheck::Warn,
+                    "abort" => config.overlap_check = OverlapCheck::Abort,
+                    _ => warn!("invalid overlap_check '{}', expected warn or abort", value),
+                },
+                "memtest" => match parse_bool(value) {
+                    Some(b) => config.memtest = b,
+                    None => warn!("invalid memtest '{}', expected on or off", value),
+                },
+                "progress" => match parse_bool(value) {
+                    Some(b) => config.progress = b,
+                    None => warn!("invalid progress '{}', expected true or false", value),
+                },
+                "progress_fg" => match parse_color(value) {
+                    Some(color) => config.progress_fg = color,
+                    None => warn!("invalid progress_fg '{}', expected RRGGBB", value),
+                },
+                "progress_bg" => match parse_color(value) {
+                    Some(color) => config.progress_bg = color,
+                    None => warn!("invalid progress_bg '{}', expected RRGGBB", value),
+                },
+                // may be meant for the kernel, which gets the whole file through the eboot table
+                _ => info!("unknown config key {}, left for the kernel", key),
+            }
+        }
+
+        config
+    }
+}
+
+/// Parse a boolean key, every spelling is accepted for every key
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse an address written in hex with a `0x` prefix, or in decimal without one
+fn parse_address(value: &str) -> Option<u64> {
+    let hex = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"));
+    match hex {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parse a color written as 6 hex digits, RRGGBB
+fn parse_color(value: &str) -> Option<u32> {
+    if value.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(value, 16).ok()
+}
+
+/// Split a comma separated list of module names
+fn parse_modules(value: &str) -> Result<ArrayVec<ArrayString<64>, MAX_BOOT_MODULES>, ConfigError> {
+    let names = || value.split(',').map(str::trim).filter(|n| !n.is_empty());
+    let count = names().count();
+    if count > MAX_BOOT_MODULES {
+        return Err(ConfigError::TooManyModules { count });
+    }
+
+    let mut modules = ArrayVec::new();
+    for name in names() {
+        let name = ArrayString::from(name)
+            .map_err(|_| ConfigError::ModuleNameTooLong { len: name.len() })?;
+        modules.push(name);
+    }
+    Ok(modules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        let config = BootConfig::parse(b"");
+        assert_eq!(config.kernel.as_str(), DEFAULT_KERNEL_NAME);
+        assert_eq!(config.stack_size, DEFAULT_STACK_SIZE);
+        assert!(config.load == LoadMode::Virtual);
+        assert!(config.modules.is_empty());
+    }
+
+    #[test]
+    fn keys_are_parsed() {
+        let config = BootConfig::parse(
+            b"# newt.cfg\n\
+              volume = NEWT\n\
+              kernel = \\boot\\KERNEL-*\n\
+              \n\
+              modules = init.mod, console.mod,\n\
+              cmdline = root=/dev/sda1 debug\n\
+              timeout = 5\n\
+              load = physical\n\
+              load_base = 0x1000000\n\
+              acpi_scan = yes\n\
+              log_level = debug\n\
+              handoff = boot-services\n\
+              progress_fg = FF8000\n",
+        );
+        assert_eq!(config.volume.as_deref(), Some("NEWT"));
+        assert_eq!(config.kernel.as_str(), "\\boot\\KERNEL-*");
+        let modules: Vec<&str> = config.modules.iter().map(|m| m.as_str()).collect();
+        assert_eq!(modules, ["init.mod", "console.mod"]);
+        // only the first = splits the line
+        assert_eq!(config.cmdline.as_deref(), Some("root=/dev/sda1 debug"));
+        assert_eq!(config.timeout, 5);
+        assert!(config.load == LoadMode::Physical);
+        assert_eq!(config.load_base, Some(0x100_0000));
+        assert!(config.acpi_scan);
+        assert_eq!(config.log_level, log::LevelFilter::Debug);
+        assert!(config.handoff == Handoff::BootServices);
+        assert_eq!(config.progress_fg, 0xFF8000);
+    }
+
+    #[test]
+    fn invalid_values_keep_defaults() {
+        let config = BootConfig::parse(
+            b"stack_size = 0\n\
+              load = sideways\n\
+              load_base = 0x1001\n\
+              read_retries = 99\n\
+              progress_bg = 12345\n\
+              not a key value line\n",
+        );
+        assert_eq!(config.stack_size, DEFAULT_STACK_SIZE);
+        assert!(config.load == LoadMode::Virtual);
+        assert_eq!(config.load_base, None);
+        assert_eq!(config.read_retries, DEFAULT_READ_RETRIES);
+        assert_eq!(config.progress_bg, 0x000000);
+    }
+
+    #[test]
+    fn invalid_utf8_gives_defaults() {
+        let config = BootConfig::parse(b"kernel = \xFF\xFE\n");
+        assert_eq!(config.kernel.as_str(), DEFAULT_KERNEL_NAME);
+    }
+
+    #[test]
+    fn crlf_line_endings_are_accepted() {
+        let config = BootConfig::parse(b"kernel = VMNEWT\r\ntimeout = 3\r\n");
+        assert_eq!(config.kernel.as_str(), "VMNEWT");
+        assert_eq!(config.timeout, 3);
+    }
+
+    #[test]
+    fn boolean_keys_accept_every_spelling() {
+        for (value, expected) in [("on", true), ("true", true), ("off", false), ("no", false)] {
+            let text = format!("kaslr = {0}\nmemtest = {0}\nacpi_scan = {0}\n", value);
+            let config = BootConfig::parse(text.as_bytes());
+            assert_eq!(config.kaslr, expected, "kaslr = {}", value);
+            assert_eq!(config.memtest, expected, "memtest = {}", value);
+            assert_eq!(config.acpi_scan, expected, "acpi_scan = {}", value);
+        }
+    }
+
+    #[test]
+    fn invalid_modules_fail_the_boot() {
+        let list: Vec<String> = (0..=MAX_BOOT_MODULES).map(|i| format!("m{}", i)).collect();
+        let text = format!("modules = {}\n", list.join(","));
+        let config = BootConfig::parse(text.as_bytes());
+        assert_eq!(
+            config.error,
+            Some(ConfigError::TooManyModules {
+                count: MAX_BOOT_MODULES + 1
+            })
+        );
+
+        let text = format!("modules = init.mod,{}\n", "m".repeat(65));
+        let config = BootConfig::parse(text.as_bytes());
+        assert_eq!(
+            config.error,
+            Some(ConfigError::ModuleNameTooLong { len: 65 })
+        );
+
+        let config = BootConfig::parse(b"modules = init.mod\n");
+        assert_eq!(config.error, None);
+        assert_eq!(config.modules.len(), 1);
+    }
+}
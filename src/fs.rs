@@ -0,0 +1,335 @@
+//! Matching and reading files on the boot volume, the parts that don't need the File protocol
+//! itself so they can be tested on the host.
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// `EFI_FILE_DIRECTORY`, the attribute bit marking a directory entry as a directory
+pub const FILE_DIRECTORY: u64 = 0x10;
+
+/// Whether the directory entry `entry` with `attribute` bits is the regular file `name`. ARCHIVE
+/// is only a backup hint and isn't set by every tool that writes the ESP, so any entry that isn't
+/// a directory counts, whatever other bits it has. FAT names are case insensitive.
+pub fn is_named_file(entry: &str, attribute: u64, name: &str) -> bool {
+    attribute & FILE_DIRECTORY == 0 && entry.eq_ignore_ascii_case(name)
+}
+
+/// The part of `entry` standing in for the `*` of a `prefix*suffix` pattern, None if it doesn't
+/// match. FAT names are case insensitive, get() rather than slicing for non-ASCII names.
+pub fn pattern_version<'a>(entry: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    let version_end = entry.len().checked_sub(suffix.len())?;
+    match (
+        entry.get(..prefix.len()),
+        entry.get(prefix.len()..version_end),
+        entry.get(version_end..),
+    ) {
+        (Some(head), Some(version), Some(end))
+            if head.eq_ignore_ascii_case(prefix) && end.eq_ignore_ascii_case(suffix) =>
+        {
+            Some(version)
+        }
+        _ => None,
+    }
+}
+
+/// Compare version strings like `5.10` and `5.9` by their numeric components, separated by `.`
+/// or `-`. Versions that aren't all numbers are compared as plain strings instead.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parts = |v: &str| {
+        v.split(|c| c == '.' || c == '-')
+            .map(|p| p.parse::<u64>().ok())
+            .collect::<Vec<_>>()
+    };
+    let (pa, pb) = (parts(a), parts(b));
+    if pa.iter().chain(&pb).all(Option::is_some) {
+        pa.cmp(&pb)
+    } else {
+        a.cmp(b)
+    }
+}
+
+/// Why a read into a caller supplied buffer failed
+#[derive(Debug, PartialEq)]
+pub enum ReadError<E> {
+    /// The buffer is too small, the read needs this many bytes
+    TooSmall(usize),
+    Other(E),
+}
+
+/// Call `read` with `buf` until it fits, replacing `buf` with a bigger one whenever `read`
+/// reports it too small. A FileInfo for a long file name easily needs more than 128 bytes.
+pub fn read_growing<T, E>(
+    buf: &mut Vec<u8>,
+    mut read: impl FnMut(&mut [u8]) -> Result<T, ReadError<E>>,
+) -> Result<T, E> {
+    loop {
+        match read(buf) {
+            Ok(value) => return Ok(value),
+            // always grow, a size that doesn't fit either would otherwise be retried forever
+            Err(ReadError::TooSmall(size)) => *buf = vec![0u8; size.max(buf.len() + 1)],
+            Err(ReadError::Other(e)) => return Err(e),
+        }
+    }
+}
+
+/// Call `read` with the offset and rest of `buf` until `buf` is full or `read` returns 0 at the
+/// end of the file, returning the bytes read. File::read is allowed to return less than was asked
+/// for, so a single call isn't enough.
+pub fn fill<E>(
+    buf: &mut [u8],
+    mut read: impl FnMut(u64, &mut [u8]) -> Result<usize, E>,
+) -> Result<usize, E> {
+    let mut total = 0;
+    while total < buf.len() {
+        let bytes = read(total as u64, &mut buf[total..])?;
+        if bytes == 0 {
+            break;
+        }
+        total += bytes;
+    }
+    Ok(total)
+}
+
+/// How reading a whole file went wrong, see `read_exact`
+#[derive(Debug, PartialEq)]
+pub enum SizeError<E> {
+    Io(E),
+    /// The file ended after `read` bytes
+    Short {
+        read: usize,
+    },
+    /// The file has more data past the size it was expected to have
+    Long,
+}
+
+/// Read the whole of a file that should be `size` bytes long with `read`, see `fill`. The file
+/// must end right after those bytes, a size from FileInfo that is off in either direction would
+/// otherwise leave a truncated image to be parsed.
+pub fn read_exact<E>(
+    size: usize,
+    mut read: impl FnMut(u64, &mut [u8]) -> Result<usize, E>,
+) -> Result<Vec<u8>, SizeError<E>> {
+    let mut buf = vec![0u8; size];
+    let total = fill(&mut buf, &mut read).map_err(SizeError::Io)?;
+    if total < size {
+        return Err(SizeError::Short { read: total });
+    }
+    if read(size as u64, &mut [0u8; 1]).map_err(SizeError::Io)? != 0 {
+        return Err(SizeError::Long);
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::tests::{image, Seg};
+    use crate::PAGE_SIZE;
+    use goblin::elf::Elf;
+
+    // the rest of the attribute bits from the spec
+    const FILE_READ_ONLY: u64 = 0x01;
+    const FILE_HIDDEN: u64 = 0x02;
+    const FILE_ARCHIVE: u64 = 0x20;
+
+    #[test]
+    fn archive_with_other_bits_is_a_file() {
+        assert!(is_named_file(
+            "KERNEL",
+            FILE_ARCHIVE | FILE_READ_ONLY,
+            "KERNEL"
+        ));
+        assert!(is_named_file(
+            "KERNEL",
+            FILE_ARCHIVE | FILE_HIDDEN,
+            "KERNEL"
+        ));
+        assert!(is_named_file("KERNEL", 0, "KERNEL"));
+    }
+
+    #[test]
+    fn directory_is_not_a_file() {
+        assert!(!is_named_file("KERNEL", FILE_DIRECTORY, "KERNEL"));
+        assert!(!is_named_file(
+            "KERNEL",
+            FILE_DIRECTORY | FILE_ARCHIVE,
+            "KERNEL"
+        ));
+    }
+
+    #[test]
+    fn names_match_ignoring_case() {
+        for entry in ["KERNEL", "kernel", "Kernel", "kErNeL"] {
+            assert!(is_named_file(entry, FILE_ARCHIVE, "KERNEL"), "{}", entry);
+        }
+        assert!(is_named_file("newt.cfg", FILE_ARCHIVE, "NEWT.CFG"));
+        assert!(!is_named_file("KERNEL2", FILE_ARCHIVE, "KERNEL"));
+        assert!(!is_named_file("KERNE", FILE_ARCHIVE, "KERNEL"));
+    }
+
+    /// Reads like Directory::read_entry of a FileInfo for `name`, which is 80 bytes followed by
+    /// the name as a NUL terminated UCS-2 string
+    fn read_entry(buf: &mut [u8], name: &str) -> Result<usize, ReadError<()>> {
+        let size = 80 + 2 * (name.len() + 1);
+        if buf.len() < size {
+            return Err(ReadError::TooSmall(size));
+        }
+        Ok(size)
+    }
+
+    #[test]
+    fn long_file_name_regrows_buffer() {
+        let name =
+            "a-kernel-with-a-file-name-long-enough-that-its-file-info-needs-more-than-128-bytes";
+        let mut buf = vec![0u8; 128];
+        let mut reads = 0;
+
+        let size = read_growing(&mut buf, |buf| {
+            reads += 1;
+            read_entry(buf, name)
+        });
+
+        assert_eq!(size, Ok(80 + 2 * (name.len() + 1)));
+        assert_eq!(buf.len(), 80 + 2 * (name.len() + 1));
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn short_file_name_fits_first_time() {
+        let mut buf = vec![0u8; 128];
+        assert_eq!(
+            read_growing(&mut buf, |buf| read_entry(buf, "KERNEL")),
+            Ok(94)
+        );
+        assert_eq!(buf.len(), 128);
+    }
+
+    #[test]
+    fn other_errors_are_returned() {
+        let mut buf = vec![0u8; 128];
+        let result: Result<(), _> =
+            read_growing(&mut buf, |_| Err(ReadError::Other("device error")));
+        assert_eq!(result, Err("device error"));
+    }
+
+    #[test]
+    fn size_that_already_failed_still_grows() {
+        let mut buf = vec![0u8; 128];
+        let result = read_growing(&mut buf, |buf| match buf.len() {
+            len if len < 130 => Err(ReadError::<()>::TooSmall(64)),
+            len => Ok(len),
+        });
+        assert_eq!(result, Ok(130));
+    }
+
+    /// Reads like File::read of `file`, at most `chunk` bytes at a time
+    fn read_chunks<'a>(
+        file: &'a [u8],
+        chunk: usize,
+        reads: &'a mut usize,
+    ) -> impl FnMut(u64, &mut [u8]) -> Result<usize, ()> + 'a {
+        move |offset, buf| {
+            *reads += 1;
+            let rest = &file[offset as usize..];
+            let len = rest.len().min(buf.len()).min(chunk);
+            buf[..len].copy_from_slice(&rest[..len]);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn page_multiple_kernel_is_read_whole() {
+        let len = 3 * PAGE_SIZE as usize;
+        let kernel = image(&[Seg::load(0x1000, 0x20_0000, 0x2000, 0x2000)], len);
+
+        let mut reads = 0;
+        let mut buf = vec![0u8; len];
+        assert_eq!(
+            fill(&mut buf, read_chunks(&kernel, 1000, &mut reads)),
+            Ok(len)
+        );
+        assert_eq!(buf, kernel);
+        // the buffer is exactly the file, no read past its end is needed to fill it
+        assert_eq!(reads, (len + 999) / 1000);
+
+        let obj = Elf::parse(&buf).unwrap();
+        let ph = &obj.program_headers[0];
+        assert_eq!(&buf[ph.file_range()], &kernel[0x1000..]);
+    }
+
+    #[test]
+    fn fill_stops_at_end_of_file() {
+        let file = [0x5A; 100];
+        let mut reads = 0;
+        let mut buf = [0u8; 128];
+        assert_eq!(fill(&mut buf, read_chunks(&file, 64, &mut reads)), Ok(100));
+        assert_eq!(reads, 3);
+    }
+
+    #[test]
+    fn file_of_the_expected_size_is_read() {
+        let file = [0x5A; 300];
+        let mut reads = 0;
+        assert_eq!(
+            read_exact(300, read_chunks(&file, 128, &mut reads)),
+            Ok(file.to_vec())
+        );
+        // three to fill the buffer, one more to find the end of the file
+        assert_eq!(reads, 4);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let file = [0x5A; 300];
+        let mut reads = 0;
+        assert_eq!(
+            read_exact(512, read_chunks(&file, 128, &mut reads)),
+            Err(SizeError::Short { read: 300 })
+        );
+    }
+
+    #[test]
+    fn file_longer_than_expected_is_an_error() {
+        let file = [0x5A; 300];
+        let mut reads = 0;
+        assert_eq!(
+            read_exact(256, read_chunks(&file, 128, &mut reads)),
+            Err(SizeError::Long)
+        );
+    }
+
+    #[test]
+    fn read_errors_are_returned() {
+        let result = read_exact(16, |_, _| Err::<usize, _>("device error"));
+        assert_eq!(result, Err(SizeError::Io("device error")));
+    }
+
+    #[test]
+    fn pattern_version_ignores_case() {
+        assert_eq!(pattern_version("KERNEL-5.10", "KERNEL-", ""), Some("5.10"));
+        assert_eq!(
+            pattern_version("kernel-5.10.efi", "KERNEL-", ".EFI"),
+            Some("5.10")
+        );
+        assert_eq!(pattern_version("KERNEL-", "KERNEL-", ""), Some(""));
+        assert_eq!(pattern_version("INITRD-5.10", "KERNEL-", ""), None);
+        assert_eq!(pattern_version("K", "KERNEL-", ".EFI"), None);
+        // a multi-byte character across the prefix boundary doesn't match rather than panic
+        assert_eq!(pattern_version("KERNEL\u{e9}5", "KERNEL-", ""), None);
+    }
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert_eq!(compare_versions("5.10", "5.9"), Ordering::Greater);
+        assert_eq!(compare_versions("5.9", "5.10"), Ordering::Less);
+        assert_eq!(compare_versions("5.10-2", "5.10-10"), Ordering::Less);
+        assert_eq!(compare_versions("5.10", "5.10"), Ordering::Equal);
+        assert_eq!(compare_versions("5.10.1", "5.10"), Ordering::Greater);
+    }
+
+    #[test]
+    fn non_numeric_versions_compare_as_strings() {
+        assert_eq!(compare_versions("rc2", "rc10"), Ordering::Greater);
+        assert_eq!(compare_versions("5.10-rc1", "5.9"), Ordering::Less);
+    }
+}
@@ -0,0 +1,264 @@
+//! Graphics Output Protocol setup, so the kernel still has a framebuffer once boot services are gone.
+//!
+//! The framebuffer is also used to draw a progress bar while the kernel is loaded, as reading a
+//! large image can take long enough on slow media to look like a hang.
+
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+use uefi::table::boot::{AllocateType, BootServices};
+use uefi::Status;
+
+/// Linear framebuffer description captured from the active GOP mode
+pub struct FramebufferInfo {
+    /// Physical address of the framebuffer
+    pub base: u64,
+    /// Size of the framebuffer in bytes
+    pub size: usize,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scanline, may be larger than `width`
+    pub stride: u32,
+    pub format: PixelFormat,
+}
+
+/// Reasons the GOP couldn't be set up
+#[derive(Debug)]
+pub enum Error {
+    /// The GOP refused to switch to a mode with a linear framebuffer
+    SetMode(Status),
+    /// No pages could be reserved for the video mode list
+    Alloc(Status),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::SetMode(status) => write!(f, "unable to set GOP mode: {:?}", status),
+            Error::Alloc(status) => {
+                write!(
+                    f,
+                    "unable to allocate pages for video mode list: {:?}",
+                    status
+                )
+            }
+        }
+    }
+}
+
+/// Locate the GOP and record the current mode, switching to the largest directly addressable mode
+/// if the current one only supports Blt operations. None on headless systems without a GOP, the
+/// kernel then sees `fb_base == 0`.
+pub fn init_framebuffer(bt: &BootServices) -> Result<Option<FramebufferInfo>, Error> {
+    let gop = match bt.locate_protocol::<GraphicsOutput>() {
+        Ok(gop) => gop.log(),
+        Err(_) => {
+            info!("no graphics output available");
+            return Ok(None);
+        }
+    };
+    let gop = unsafe { &mut *gop.get() };
+
+    if gop.current_mode_info().pixel_format() == PixelFormat::BltOnly {
+        warn!("Current GOP mode has no linear framebuffer, looking for another mode");
+
+        let best = gop
+            .modes()
+            .map(|m| m.log())
+            .filter(|m| m.info().pixel_format() != PixelFormat::BltOnly)
+            .max_by_key(|m| {
+                let (w, h) = m.info().resolution();
+                w * h
+            });
+
+        match best {
+            Some(mode) => gop
+                .set_mode(&mode)
+                .map_err(|e| Error::SetMode(e.status()))?
+                .log(),
+            None => {
+                warn!("No GOP mode with a linear framebuffer available");
+                return Ok(None);
+            }
+        }
+    }
+
+    let info = gop.current_mode_info();
+    let (width, height) = info.resolution();
+    let mut fb = gop.frame_buffer();
+
+    let fb_info = FramebufferInfo {
+        base: fb.as_mut_ptr() as u64,
+        size: fb.size(),
+        width: width as u32,
+        height: height as u32,
+        stride: info.stride() as u32,
+        format: info.pixel_format(),
+    };
+
+    info!(
+        "Framebuffer @ {:#X} ({} bytes) {}x{} stride {} format {:?}",
+        fb_info.base, fb_info.size, fb_info.width, fb_info.height, fb_info.stride, fb_info.format
+    );
+
+    Ok(Some(fb_info))
+}
+
+/// Maximum number of GOP modes reported to the kernel
+const MAX_VIDEO_MODES: usize = 64;
+
+/// A mode the GOP offered, as reported to the kernel through `EBootTable::video_modes_ptr`.
+/// Only informational, SetMode can't be called once boot services have been exited.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VideoMode {
+    /// GOP mode number
+    pub mode: u32,
+    pub width: u32,
+    pub height: u32,
+    /// `EFI_GRAPHICS_PIXEL_FORMAT` of the mode
+    pub format: u32,
+}
+
+/// Record up to `MAX_VIDEO_MODES` of the modes the GOP supports in `memtype::BOOT_INFO` pages,
+/// empty if there is no GOP
+pub fn list_modes(bt: &BootServices) -> Result<&'static [VideoMode], Error> {
+    let gop = match bt.locate_protocol::<GraphicsOutput>() {
+        Ok(gop) => gop.log(),
+        Err(_) => return Ok(&[]),
+    };
+    let gop = unsafe { &mut *gop.get() };
+
+    let size = MAX_VIDEO_MODES * core::mem::size_of::<VideoMode>();
+    let pages = (size + crate::PAGE_SIZE as usize - 1) / crate::PAGE_SIZE as usize;
+    let table = bt
+        .allocate_pages(AllocateType::AnyPages, crate::memtype::BOOT_INFO, pages)
+        .map_err(|e| Error::Alloc(e.status()))?
+        .log() as *mut VideoMode;
+
+    let mut count = 0;
+    for (index, mode) in gop.modes().map(|m| m.log()).enumerate() {
+        if count == MAX_VIDEO_MODES {
+            warn!(
+                "GOP has more than {} modes, only reporting the first {}",
+                MAX_VIDEO_MODES, MAX_VIDEO_MODES
+            );
+            break;
+        }
+        let (width, height) = mode.info().resolution();
+        unsafe {
+            table.add(count).write(VideoMode {
+                mode: index as u32,
+                width: width as u32,
+                height: height as u32,
+                format: mode.info().pixel_format() as u32,
+            })
+        };
+        count += 1;
+    }
+
+    info!("Found {} GOP modes", count);
+    Ok(unsafe { core::slice::from_raw_parts(table, count) })
+}
+
+/// Points during boot the progress bar advances at, in order
+#[derive(Clone, Copy)]
+pub enum Stage {
+    FoundKernel,
+    Read,
+    Parsed,
+    SegmentsLoaded,
+    ExitingBootServices,
+}
+
+const STAGE_COUNT: u32 = 5;
+
+/// Progress bar drawn directly into the linear framebuffer, so it keeps working after boot
+/// services have been exited
+pub struct ProgressBar {
+    base: *mut u32,
+    width: u32,
+    height: u32,
+    stride: u32,
+    fg: u32,
+    bg: u32,
+}
+
+impl ProgressBar {
+    /// Clear the screen to `bg` and draw an empty bar, colors are 0xRRGGBB.
+    /// Returns None for bitmask pixel formats, which aren't worth decoding just for this, and for
+    /// modes too small to fit the bar.
+    pub fn new(fb: &FramebufferInfo, fg: u32, bg: u32) -> Option<ProgressBar> {
+        let (fg, bg) = match fb.format {
+            PixelFormat::Rgb => (rgb_to_pixel(fg), rgb_to_pixel(bg)),
+            PixelFormat::Bgr => (fg, bg),
+            _ => {
+                warn!(
+                    "Unsupported pixel format {:?}, not drawing progress",
+                    fb.format
+                );
+                return None;
+            }
+        };
+
+        let bar = ProgressBar {
+            base: fb.base as *mut u32,
+            width: fb.width,
+            height: fb.height,
+            stride: fb.stride,
+            fg,
+            bg,
+        };
+        let (x, y, w, h) = match bar.bounds() {
+            Some(bounds) => bounds,
+            None => {
+                warn!(
+                    "{}x{} mode is too small for a progress bar",
+                    fb.width, fb.height
+                );
+                return None;
+            }
+        };
+        bar.fill(0, 0, bar.width, bar.height, bar.bg);
+
+        // 1px outline around where the bar fills in
+        bar.fill(x - 1, y - 1, w + 2, 1, bar.fg);
+        bar.fill(x - 1, y + h, w + 2, 1, bar.fg);
+        bar.fill(x - 1, y, 1, h, bar.fg);
+        bar.fill(x + w, y, 1, h, bar.fg);
+        Some(bar)
+    }
+
+    /// Fill the bar up to and including `stage`
+    pub fn advance(&self, stage: Stage) {
+        if let Some((x, y, w, h)) = self.bounds() {
+            let filled = (w as u64 * (stage as u64 + 1) / STAGE_COUNT as u64) as u32;
+            self.fill(x, y, filled, h, self.fg);
+        }
+    }
+
+    /// Half the screen wide, centered horizontally three quarters of the way down. None if the
+    /// bar and the outline one pixel outside it don't fit on the screen.
+    fn bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let w = self.width / 2;
+        let h = (self.height / 40).max(4);
+        let (x, y) = (self.width / 4, (self.height as u64 * 3 / 4) as u32);
+        let fits = w > 0 && x > 0 && y > 0 && x + w < self.width;
+        match y.checked_add(h) {
+            Some(bottom) if fits && bottom < self.height => Some((x, y, w, h)),
+            _ => None,
+        }
+    }
+
+    fn fill(&self, x: u32, y: u32, w: u32, h: u32, pixel: u32) {
+        for row in y..y + h {
+            for col in x..x + w {
+                let offset = row as usize * self.stride as usize + col as usize;
+                unsafe { self.base.add(offset).write_volatile(pixel) };
+            }
+        }
+    }
+}
+
+/// Swap 0xRRGGBB into the byte order of PixelFormat::Rgb, red in the lowest byte
+fn rgb_to_pixel(rgb: u32) -> u32 {
+    (rgb & 0xFF) << 16 | (rgb & 0xFF00) | (rgb >> 16) & 0xFF
+}
@@ -0,0 +1,162 @@
+//! Decompression of gzip (RFC 1952) wrapped kernel images.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use miniz_oxide::inflate::core::{decompress as inflate, inflate_flags, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// The only compression method defined by the spec
+const CM_DEFLATE: u8 = 8;
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+/// Fixed part of the member header
+const HEADER_LEN: usize = 10;
+/// CRC32 and ISIZE
+const TRAILER_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The header is truncated or uses something other than deflate
+    BadHeader,
+    /// The deflate stream is corrupt or truncated
+    Inflate(TINFLStatus),
+    /// The stream didn't decompress to the size recorded in the trailer
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::BadHeader => write!(f, "invalid gzip header"),
+            Error::Inflate(status) => write!(f, "inflate failed: {:?}", status),
+            Error::SizeMismatch { expected, actual } => write!(
+                f,
+                "decompressed {} bytes, gzip trailer says {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Decompress a single member gzip file. The output buffer is sized from the ISIZE field of the
+/// trailer, so the whole image is inflated in one pass without growing it.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < HEADER_LEN + TRAILER_LEN || !is_gzip(data) || data[2] != CM_DEFLATE {
+        return Err(Error::BadHeader);
+    }
+
+    let flags = data[3];
+    let mut pos = HEADER_LEN;
+    if flags & FEXTRA != 0 {
+        let xlen = u16::from_le_bytes([
+            *data.get(pos).ok_or(Error::BadHeader)?,
+            *data.get(pos + 1).ok_or(Error::BadHeader)?,
+        ]) as usize;
+        pos += 2 + xlen;
+    }
+    // the name and comment are NUL terminated
+    for field in [FNAME, FCOMMENT] {
+        if flags & field != 0 {
+            let len = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or(Error::BadHeader)?;
+            pos += len + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let trailer = data.len() - TRAILER_LEN;
+    if pos > trailer {
+        return Err(Error::BadHeader);
+    }
+    let isize = u32::from_le_bytes(data[trailer + 4..].try_into().unwrap()) as usize;
+
+    let mut out = vec![0u8; isize];
+    // the decompressor state is too big to comfortably keep on the stack
+    let mut state = Box::<DecompressorOxide>::default();
+    let (status, _read, written) = inflate(
+        &mut state,
+        &data[pos..trailer],
+        &mut out,
+        0,
+        inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    );
+
+    match status {
+        TINFLStatus::Done if written == isize => Ok(out),
+        TINFLStatus::Done | TINFLStatus::HasMoreOutput => Err(Error::SizeMismatch {
+            expected: isize,
+            actual: written,
+        }),
+        other => Err(Error::Inflate(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::segment::tests::{image, Seg};
+    use miniz_oxide::deflate::compress_to_vec;
+
+    /// `data` as gzip would write it, with `name` in the header if given
+    fn gzip(data: &[u8], name: Option<&[u8]>) -> Vec<u8> {
+        let flags = if name.is_some() { FNAME } else { 0 };
+        let mut out = vec![0x1f, 0x8b, CM_DEFLATE, flags, 0, 0, 0, 0, 0, 3];
+        if let Some(name) = name {
+            out.extend_from_slice(name);
+            out.push(0);
+        }
+        out.extend_from_slice(&compress_to_vec(data, 6));
+        out.extend_from_slice(&crc32(data).to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn elf_round_trips() {
+        let elf = image(&[Seg::load(0x1000, 0x20_0000, 0x100, 0x2000)], 0x2000);
+        let compressed = gzip(&elf, Some(b"KERNEL"));
+        assert!(is_gzip(&compressed));
+        assert!(compressed.len() < elf.len());
+
+        let decompressed = decompress(&compressed).unwrap();
+        assert_eq!(decompressed, elf);
+        assert!(goblin::elf::Elf::parse(&decompressed).is_ok());
+    }
+
+    #[test]
+    fn wrong_trailer_size_is_rejected() {
+        let mut compressed = gzip(&[0x5A; 0x1000], None);
+        let len = compressed.len();
+        compressed[len - 4..].copy_from_slice(&0x800u32.to_le_bytes());
+
+        assert!(matches!(
+            decompress(&compressed),
+            Err(Error::SizeMismatch {
+                expected: 0x800,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn raw_elf_is_not_gzip() {
+        let elf = image(&[Seg::load(0x1000, 0x20_0000, 0x100, 0x100)], 0x2000);
+        assert!(!is_gzip(&elf));
+        assert!(matches!(decompress(&elf), Err(Error::BadHeader)));
+    }
+}
@@ -0,0 +1,102 @@
+//! Randomized load address for position independent kernels, `kaslr = on`.
+//!
+//! The kernel is slid above `load_base` by a random multiple of `SLIDE_ALIGN`, so any alignment
+//! up to 2 MiB the kernel was linked with is kept. Entropy comes from the UEFI RNG protocol if
+//! the firmware has one, otherwise from the TSC on x86_64. The slide only goes where the whole
+//! kernel fits in free memory, a few slots are tried before giving up and using the base as is.
+
+use uefi::prelude::*;
+use uefi::table::boot::MemoryType;
+
+use crate::config::MAX_LOAD_BASE;
+use crate::PAGE_SIZE;
+
+/// The slide is a multiple of this
+const SLIDE_ALIGN: u64 = 0x20_0000;
+
+/// Number of possible slides, spreading the kernel over 1 GiB above the base
+const SLIDE_SLOTS: u64 = 512;
+
+/// Random slots tried before falling back to no slide
+const ATTEMPTS: usize = 16;
+
+/// Pick a slide for a kernel occupying `[start, end)` once loaded at the base, 0 if there is no
+/// entropy source or no free slot was found
+pub fn pick_offset(bt: &BootServices, start: u64, end: u64) -> u64 {
+    let mut state = match seed(bt) {
+        Some(seed) => seed,
+        None => {
+            warn!("No entropy source for KASLR, loading at the fixed base");
+            return 0;
+        }
+    };
+
+    let mut mmap_buf = crate::create_mmap_buf(bt);
+    let descriptors = match bt.memory_map(&mut mmap_buf) {
+        Ok(map) => map.log().1,
+        Err(e) => {
+            warn!(
+                "Unable to read the memory map for KASLR: {:?}, loading at the fixed base",
+                e.status()
+            );
+            return 0;
+        }
+    };
+
+    let start = start & !(PAGE_SIZE - 1);
+    let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    for _ in 0..ATTEMPTS {
+        let offset = (splitmix64(&mut state) % SLIDE_SLOTS + 1) * SLIDE_ALIGN;
+        let (slid_start, slid_end) = (start + offset, end + offset);
+        if slid_end > MAX_LOAD_BASE {
+            continue;
+        }
+        // neighbouring free descriptors aren't merged, which at worst costs an attempt
+        let free = descriptors.clone().any(|d| {
+            d.ty == MemoryType::CONVENTIONAL
+                && d.phys_start <= slid_start
+                && d.phys_start + d.page_count * PAGE_SIZE >= slid_end
+        });
+        if free {
+            info!("KASLR slide {:#X}", offset);
+            return offset;
+        }
+    }
+
+    warn!(
+        "No free memory for a KASLR slide after {} attempts, loading at the fixed base",
+        ATTEMPTS
+    );
+    0
+}
+
+/// Seed from the RNG protocol, or the TSC if there is none
+fn seed(bt: &BootServices) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    if crate::rng::fill(bt, &mut bytes) {
+        debug!("KASLR seeded from the RNG protocol");
+        return Some(u64::from_ne_bytes(bytes));
+    }
+    tsc()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn tsc() -> Option<u64> {
+    debug!("KASLR seeded from the TSC");
+    Some(unsafe { core::arch::x86_64::_rdtsc() })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn tsc() -> Option<u64> {
+    None
+}
+
+/// Advance `state` and return the next output, the low bits of a raw TSC read are too regular
+/// to use directly
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
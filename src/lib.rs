@@ -0,0 +1,18 @@
+//! The parts of the loader that don't need boot services, split out of the UEFI binary so they
+//! can be unit tested on the host. `make test` builds just this library for the host target.
+
+#![cfg_attr(not(test), no_std)]
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate alloc;
+
+pub mod config;
+pub mod crc32;
+pub mod fs;
+pub mod gzip;
+pub mod segment;
+
+/// Size of a UEFI page, the granularity of allocate_pages
+pub const PAGE_SIZE: u64 = 4096;
@@ -0,0 +1,93 @@
+//! Global logger writing to both the UEFI console and the serial port.
+//!
+//! This replaces the logger normally installed by `uefi_services::init`, which can only write to
+//! the UEFI console and goes quiet as soon as boot services are exited. The console half of this
+//! logger is disabled at that point too, but serial output carries on up to the kernel jump.
+//!
+//! With the `vga-text` feature the legacy VGA text buffer takes over from the console once it
+//! has been disabled.
+
+use core::ffi::c_void;
+use core::fmt::Write;
+use core::ptr::NonNull;
+
+use uefi::prelude::*;
+use uefi::table::boot::{EventType, Tpl};
+use uefi::Event;
+
+use crate::serial::{SerialPort, SERIAL_PORT_BASE};
+
+pub struct BootLogger {
+    console: Option<uefi::logger::Logger>,
+    serial: SerialPort,
+}
+
+static mut LOGGER: BootLogger = BootLogger {
+    console: None,
+    serial: SerialPort::new(SERIAL_PORT_BASE),
+};
+
+/// Set up memory allocation and logging, taking the place of `uefi_services::init`
+pub fn init(st: &mut SystemTable<Boot>) -> uefi::Result {
+    unsafe {
+        LOGGER.serial.init();
+        LOGGER.console = Some(uefi::logger::Logger::new(st.stdout()));
+
+        log::set_logger(&LOGGER).unwrap(); // Can only fail if already initialized.
+        log::set_max_level(log::LevelFilter::Info);
+
+        let boot_services = st.boot_services();
+        uefi::alloc::init(boot_services);
+
+        // the console and allocator both go away with boot services, make sure we stop using them
+        boot_services
+            .create_event(
+                EventType::SIGNAL_EXIT_BOOT_SERVICES,
+                Tpl::NOTIFY,
+                Some(exit_boot_services),
+                None,
+            )
+            .map_inner(|_| ())
+    }
+}
+
+unsafe extern "efiapi" fn exit_boot_services(_e: Event, _ctx: Option<NonNull<c_void>>) {
+    if let Some(ref mut console) = LOGGER.console {
+        console.disable();
+    }
+    LOGGER.console = None;
+
+    uefi::alloc::exit_boot_services();
+}
+
+impl log::Log for BootLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(ref console) = self.console {
+            console.log(record);
+        } else {
+            #[cfg(feature = "vga-text")]
+            let _ = writeln!(
+                crate::vga::VgaText,
+                "[{:>5}]: {}",
+                record.level(),
+                record.args()
+            );
+        }
+
+        let mut serial = self.serial;
+        let _ = writeln!(
+            serial,
+            "[{:>5}]: {:>12}@{:03}: {}",
+            record.level(),
+            record.file().unwrap_or("<unknown file>"),
+            record.line().unwrap_or(0),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
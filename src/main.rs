@@ -1,7 +1,9 @@
 #![no_std]
 #![no_main]
 #![feature(ptr_internals)]
-#![feature(vec_into_raw_parts)]
+#![feature(abi_efiapi)]
+#![feature(const_ptr_offset_from)]
+#![feature(negative_impls)]
 
 #[macro_use]
 extern crate log;
@@ -13,326 +15,3070 @@ extern crate rlibc;
 
 extern crate goblin;
 extern crate uefi;
+// only linked for its allocation error handler, logging and panics are handled by this crate
 extern crate uefi_services;
 
-use alloc::boxed::Box;
+mod acpi;
+mod block;
+mod bootonce;
+mod capsule;
+mod gop;
+mod kaslr;
+mod logger;
+mod memtest;
+mod memtype;
+mod menu;
+mod mmap;
+mod mp;
+#[cfg(target_arch = "x86_64")]
+mod paging;
+mod panic;
+#[cfg(feature = "profile")]
+mod profile;
+mod rng;
+mod serial;
+mod sha256;
+mod smbios;
+#[cfg(feature = "vga-text")]
+mod vga;
+
 use alloc::vec::Vec;
+use core::arch::asm;
+use core::ffi::c_void;
 use core::mem::MaybeUninit;
 
 use arrayvec::ArrayVec;
-use uefi::proto::media::file::{File, FileAttribute, FileInfo};
-use uefi::proto::media::file::{FileHandle, FileType};
+use config::{BootConfig, Dump, EntryAbi, Handoff, LoadMode, OverlapCheck, Watchdog};
+use goblin::elf::{header, program_header, reloc};
+#[cfg(feature = "verify-copy")]
+use newt_stub::crc32;
+use newt_stub::segment::{self, page_span};
+use newt_stub::{config, fs, gzip, PAGE_SIZE};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::{Directory, File, FileAttribute, FileInfo, FileSystemVolumeLabel};
+use uefi::proto::media::file::{FileHandle, FileMode, FileType, RegularFile};
 use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::proto::pi::mp::ProcessorCount;
+use uefi::table::boot::MEMORY_DESCRIPTOR_VERSION;
+use uefi::table::boot::{AllocateType, MemoryAttribute, MemoryDescriptor, MemoryType};
 use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType};
 use uefi::table::Runtime;
 use uefi::{prelude::*, proto};
 
-const EFI_KERNEL_NAME: &str = "KERNEL";
+/// ELF machine the loader accepts kernels for, the architecture it was built for itself
+#[cfg(target_arch = "x86_64")]
+const KERNEL_MACHINE: u16 = header::EM_X86_64;
+#[cfg(target_arch = "aarch64")]
+const KERNEL_MACHINE: u16 = header::EM_AARCH64;
+
+/// How many times exit_boot_services is attempted before giving up
+const EXIT_BOOT_SERVICES_ATTEMPTS: usize = 4;
+
+/// Spare descriptor slots added to the memory map buffer on top of the current map size
+const MMAP_EXTRA_DESCRIPTORS: usize = 8;
+
+/// Value of `EBootTable::magic`, "NEWTBOOT" in ASCII. Kernels should refuse a table without it.
+pub const EBOOT_MAGIC: u64 = 0x4E45_5754_424F_4F54;
 
+/// Version of the `EBootTable` layout, bumped whenever fields are added, removed or reordered
+pub const EBOOT_ABI_VERSION: u32 = 21;
+
+/// NUL terminated loader name and version, pointed to by `EBootTable::loader_version_ptr`
+static LOADER_VERSION: &str = concat!("newt_stub ", env!("CARGO_PKG_VERSION"), "\0");
+
+/// Table handed to the kernel entry point after boot services have been exited.
+///
+/// The layout is `repr(C)`, fields appear in memory in declaration order:
+///
+/// | field               | contents                                                  |
+/// |---------------------|-----------------------------------------------------------|
+/// | `magic`             | always `EBOOT_MAGIC`                                      |
+/// | `abi_version`       | always `EBOOT_ABI_VERSION`                                |
+/// | `sys_table`         | Runtime view of the UEFI system table                     |
+/// | `mmap_buf`          | pointer to the raw UEFI memory map                        |
+/// | `mmap_len`          | length of the memory map in `mmap_buf` in bytes           |
+/// | `mmap_cap`          | capacity of the memory map buffer in bytes                |
+/// | `mmap_desc_size`    | stride between descriptors in `mmap_buf`, in bytes        |
+/// | `mmap_desc_version` | version of the `EFI_MEMORY_DESCRIPTOR` layout in the map  |
+/// | `acpi_rsdp`         | physical address of the ACPI RSDP, 2.0+ if available      |
+/// | `fb_base`           | physical address of the linear framebuffer                |
+/// | `fb_size`           | size of the framebuffer in bytes                          |
+/// | `fb_width`          | horizontal resolution in pixels                           |
+/// | `fb_height`         | vertical resolution in pixels                             |
+/// | `fb_stride`         | pixels per scanline                                       |
+/// | `fb_format`         | `EFI_GRAPHICS_PIXEL_FORMAT` of the framebuffer            |
+/// | `segments`          | pointer to an array of `KernelSegment`                    |
+/// | `segment_count`     | number of entries in `segments`                           |
+/// | `initrd_base`       | physical address of the initrd, 0 if none was loaded      |
+/// | `initrd_len`        | size of the initrd in bytes                               |
+/// | `cmdline_ptr`       | NUL terminated kernel command line, null if not set       |
+/// | `cmdline_len`       | length of the command line in bytes, excluding the NUL    |
+/// | `smbios_entry`      | physical address of the SMBIOS entry point, 3.0 preferred |
+/// | `pml4`              | PML4 loaded into CR3, 0 if the firmware's is still active |
+/// | `stack_base`        | lowest address of the stack the kernel is entered on      |
+/// | `stack_size`        | size of the kernel stack in bytes                         |
+/// | `loader_version_ptr`| NUL terminated "newt_stub x.y.z" string                   |
+/// | `sorted_mmap`       | memory map sorted by address with neighbours coalesced    |
+/// | `sorted_mmap_len`   | number of descriptors in `sorted_mmap`                    |
+/// | `boot_sys_table`    | Boot system table, only with `handoff = boot-services`    |
+/// | `image_handle`      | loader image handle, only with `handoff = boot-services`  |
+/// | `modules_ptr`       | pointer to an array of `BootModule`, null if none         |
+/// | `modules_count`     | number of entries in `modules_ptr`                        |
+/// | `video_modes_ptr`   | pointer to an array of `gop::VideoMode` the GOP offered   |
+/// | `video_modes_count` | number of entries in `video_modes_ptr`                    |
+/// | `tls_vaddr`         | address of the PT_TLS initialization image, 0 if none     |
+/// | `tls_filesz`        | size of the initialized part of the TLS image in bytes    |
+/// | `tls_memsz`         | size of the whole TLS block in bytes, including .tbss     |
+/// | `tls_align`         | required alignment of the TLS block                       |
+/// | `loader_image_base` | physical address of the loader's own image, 0 if unknown  |
+/// | `loader_image_size` | size of the loader's image in bytes, free once unused     |
+/// | `dtb_ptr`           | physical address of the devicetree blob, 0 if none        |
+/// | `dtb_len`           | size of the devicetree blob in bytes                      |
+/// | `runtime_services`  | live `EFI_RUNTIME_SERVICES` table, virtual if remapped    |
+/// | `processor_count`   | logical processors from MP Services, 0 if unavailable     |
+/// | `enabled_processor_count` | how many of those are enabled, 0 if unavailable     |
+/// | `firmware_vendor`   | NUL terminated firmware vendor, at most 32 bytes, or null |
+/// | `uefi_revision`     | `EFI_SYSTEM_TABLE.Revision`, major << 16 \| minor         |
+/// | `kaslr_offset`      | random slide added to `load_base`, 0 without `kaslr = on` |
+/// | `rng_seed_ptr`      | random bytes from `EFI_RNG_PROTOCOL`, null if unavailable |
+/// | `rng_seed_len`      | number of bytes at `rng_seed_ptr`                         |
+/// | `config_ptr`        | verbatim newt.cfg, NUL terminated, null if there was none |
+/// | `config_len`        | size of the config file in bytes, excluding the NUL       |
+///
+/// With `handoff = boot-services` the kernel is entered with boot services still running, so
+/// `sys_table` and the memory map fields are left empty and it has to exit them itself. CR3 is
+/// left on the firmware's identity map, so `pml4` is 0 as well.
+///
+/// The descriptor size reported by firmware may be larger than `size_of::<MemoryDescriptor>()`,
+/// so the kernel must always step through the map using `mmap_desc_size`.
 #[repr(C)]
 struct EBootTable {
+    magic: u64,
+    abi_version: u32,
     sys_table: Option<SystemTable<Runtime>>,
     mmap_buf: Option<*mut u8>,
     mmap_len: Option<usize>,
     mmap_cap: Option<usize>,
+    mmap_desc_size: Option<usize>,
+    mmap_desc_version: Option<u32>,
+    acpi_rsdp: Option<*const c_void>,
+    fb_base: u64,
+    fb_size: usize,
+    fb_width: u32,
+    fb_height: u32,
+    fb_stride: u32,
+    fb_format: u32,
+    segments: *const KernelSegment,
+    segment_count: usize,
+    initrd_base: u64,
+    initrd_len: usize,
+    cmdline_ptr: *const u8,
+    cmdline_len: usize,
+    smbios_entry: u64,
+    pml4: u64,
+    stack_base: u64,
+    stack_size: usize,
+    loader_version_ptr: *const u8,
+    sorted_mmap: *const MemoryDescriptor,
+    sorted_mmap_len: usize,
+    boot_sys_table: Option<SystemTable<Boot>>,
+    image_handle: Option<Handle>,
+    modules_ptr: *const BootModule,
+    modules_count: usize,
+    video_modes_ptr: *const gop::VideoMode,
+    video_modes_count: usize,
+    tls_vaddr: u64,
+    tls_filesz: u64,
+    tls_memsz: u64,
+    tls_align: u64,
+    loader_image_base: u64,
+    loader_image_size: u64,
+    dtb_ptr: u64,
+    dtb_len: usize,
+    runtime_services: *const RuntimeServices,
+    processor_count: u64,
+    enabled_processor_count: u64,
+    firmware_vendor: *const u8,
+    uefi_revision: u32,
+    kaslr_offset: u64,
+    rng_seed_ptr: *const u8,
+    rng_seed_len: usize,
+    config_ptr: *const u8,
+    config_len: usize,
+}
+
+/// Byte offset of `$field` within `$ty`, usable in const context
+macro_rules! offset_of {
+    ($ty:ty, $field:ident) => {{
+        let table = core::mem::MaybeUninit::<$ty>::uninit();
+        let base = table.as_ptr();
+        #[allow(unused_unsafe)]
+        unsafe {
+            (core::ptr::addr_of!((*base).$field) as *const u8).offset_from(base as *const u8)
+                as usize
+        }
+    }};
 }
 
+// Pin the layout kernels are built against, any reordering or resizing of a field has to be a
+// deliberate change together with an EBOOT_ABI_VERSION bump. Plain pointers are 8 bytes, but
+// `Option<usize>` and `Option` of a raw pointer are 16 since neither has a niche to store `None`
+// in, and `Option<u32>` is 8. `Option<SystemTable<_>>` and `Option<Handle>` stay 8, `None` is null.
+//
+//   0 magic              8 abi_version         16 sys_table          24 mmap_buf
+//  40 mmap_len          56 mmap_cap            72 mmap_desc_size     88 mmap_desc_version
+//  96 acpi_rsdp        112 fb_base            120 fb_size           128 fb_width
+// 132 fb_height        136 fb_stride          140 fb_format         144 segments
+// 152 segment_count    160 initrd_base        168 initrd_len        176 cmdline_ptr
+// 184 cmdline_len      192 smbios_entry       200 pml4              208 stack_base
+// 216 stack_size       224 loader_version_ptr 232 sorted_mmap       240 sorted_mmap_len
+// 248 boot_sys_table   256 image_handle       264 modules_ptr        272 modules_count
+// 280 video_modes_ptr  288 video_modes_count  296 tls_vaddr          304 tls_filesz
+// 312 tls_memsz        320 tls_align          328 loader_image_base  336 loader_image_size
+// 344 dtb_ptr          352 dtb_len            360 runtime_services   368 processor_count
+// 376 enabled_processor_count                  384 firmware_vendor    392 uefi_revision
+// 400 kaslr_offset     408 rng_seed_ptr       416 rng_seed_len       424 config_ptr
+// 432 config_len       440 end
+const _: () = {
+    assert!(core::mem::size_of::<EBootTable>() == 440);
+    assert!(core::mem::align_of::<EBootTable>() == 8);
+    assert!(offset_of!(EBootTable, magic) == 0);
+    assert!(offset_of!(EBootTable, abi_version) == 8);
+    assert!(offset_of!(EBootTable, sys_table) == 16);
+    assert!(offset_of!(EBootTable, mmap_buf) == 24);
+    assert!(offset_of!(EBootTable, mmap_desc_size) == 72);
+    assert!(offset_of!(EBootTable, acpi_rsdp) == 96);
+    assert!(offset_of!(EBootTable, fb_base) == 112);
+    assert!(offset_of!(EBootTable, segments) == 144);
+    assert!(offset_of!(EBootTable, initrd_base) == 160);
+    assert!(offset_of!(EBootTable, cmdline_ptr) == 176);
+    assert!(offset_of!(EBootTable, smbios_entry) == 192);
+    assert!(offset_of!(EBootTable, pml4) == 200);
+    assert!(offset_of!(EBootTable, stack_base) == 208);
+    assert!(offset_of!(EBootTable, loader_version_ptr) == 224);
+    assert!(offset_of!(EBootTable, sorted_mmap) == 232);
+    assert!(offset_of!(EBootTable, boot_sys_table) == 248);
+    assert!(offset_of!(EBootTable, image_handle) == 256);
+    assert!(offset_of!(EBootTable, modules_ptr) == 264);
+    assert!(offset_of!(EBootTable, video_modes_ptr) == 280);
+    assert!(offset_of!(EBootTable, tls_vaddr) == 296);
+    assert!(offset_of!(EBootTable, loader_image_base) == 328);
+    assert!(offset_of!(EBootTable, dtb_ptr) == 344);
+    assert!(offset_of!(EBootTable, runtime_services) == 360);
+    assert!(offset_of!(EBootTable, processor_count) == 368);
+    assert!(offset_of!(EBootTable, enabled_processor_count) == 376);
+    assert!(offset_of!(EBootTable, firmware_vendor) == 384);
+    assert!(offset_of!(EBootTable, uefi_revision) == 392);
+    assert!(offset_of!(EBootTable, kaslr_offset) == 400);
+    assert!(offset_of!(EBootTable, rng_seed_ptr) == 408);
+    assert!(offset_of!(EBootTable, config_ptr) == 424);
+};
+
 impl EBootTable {
-    pub unsafe fn new() -> *mut EBootTable {
-        let value = Box::new(EBootTable {
-            sys_table: None,
-            mmap_buf: None,
-            mmap_len: None,
-            mmap_cap: None,
-        });
-        Box::into_raw(value)
+    /// Reserve page aligned memory of type `memtype::BOOT_INFO` for the table and initialize it.
+    /// Pool memory can't be used, the allocator is gone once boot services have been exited.
+    pub fn new(bs: &BootServices) -> Result<&'static mut EBootTable, BootError> {
+        let size = core::mem::size_of::<EBootTable>();
+        let pages = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let table = bs
+            .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+            .map_err(|e| BootError::Alloc {
+                what: "eboot table",
+                status: e.status(),
+            })?
+            .log() as *mut EBootTable;
+
+        // the pages were just reserved for the table and nothing else refers to them
+        unsafe {
+            table.write(EBootTable {
+                magic: EBOOT_MAGIC,
+                abi_version: EBOOT_ABI_VERSION,
+                sys_table: None,
+                mmap_buf: None,
+                mmap_len: None,
+                mmap_cap: None,
+                mmap_desc_size: None,
+                mmap_desc_version: None,
+                acpi_rsdp: None,
+                fb_base: 0,
+                fb_size: 0,
+                fb_width: 0,
+                fb_height: 0,
+                fb_stride: 0,
+                fb_format: 0,
+                segments: core::ptr::null(),
+                segment_count: 0,
+                initrd_base: 0,
+                initrd_len: 0,
+                cmdline_ptr: core::ptr::null(),
+                cmdline_len: 0,
+                smbios_entry: 0,
+                pml4: 0,
+                stack_base: 0,
+                stack_size: 0,
+                loader_version_ptr: LOADER_VERSION.as_ptr(),
+                sorted_mmap: core::ptr::null(),
+                sorted_mmap_len: 0,
+                boot_sys_table: None,
+                image_handle: None,
+                modules_ptr: core::ptr::null(),
+                modules_count: 0,
+                video_modes_ptr: core::ptr::null(),
+                video_modes_count: 0,
+                tls_vaddr: 0,
+                tls_filesz: 0,
+                tls_memsz: 0,
+                tls_align: 0,
+                loader_image_base: 0,
+                loader_image_size: 0,
+                dtb_ptr: 0,
+                dtb_len: 0,
+                runtime_services: core::ptr::null(),
+                processor_count: 0,
+                enabled_processor_count: 0,
+                firmware_vendor: core::ptr::null(),
+                uefi_revision: 0,
+                kaslr_offset: 0,
+                rng_seed_ptr: core::ptr::null(),
+                rng_seed_len: 0,
+                config_ptr: core::ptr::null(),
+                config_len: 0,
+            });
+            Ok(&mut *table)
+        }
     }
 
-    pub fn update(&mut self, st: SystemTable<Runtime>, mmap_buf: Vec<u8>) {
-        let (ptr, len, cap) = mmap_buf.into_raw_parts();
+    /// `mmap_buf` holds the map exit_boot_services returned, `descriptor_count` descriptors of
+    /// `mmap_desc_size` bytes each. The rest of the buffer is slack left for the map to grow into.
+    pub fn update(
+        &mut self,
+        st: SystemTable<Runtime>,
+        mmap_buf: &'static mut [u8],
+        descriptor_count: usize,
+        mmap_desc_size: usize,
+        acpi_rsdp: Option<*const c_void>,
+    ) {
         self.sys_table = Some(st);
-        self.mmap_buf = Some(ptr);
-        self.mmap_len = Some(len);
-        self.mmap_cap = Some(cap);
+        self.mmap_buf = Some(mmap_buf.as_mut_ptr());
+        self.mmap_len = Some(descriptor_count * mmap_desc_size);
+        self.mmap_cap = Some(mmap_buf.len());
+        self.mmap_desc_size = Some(mmap_desc_size);
+        self.mmap_desc_version = Some(MEMORY_DESCRIPTOR_VERSION);
+        self.acpi_rsdp = acpi_rsdp;
+    }
+
+    /// Log the handoff state on one line, the last thing printed before the jump
+    pub fn log_summary(&self, entry: *const (), copied: u64) {
+        // with boot services still running there is no map, the kernel fetches it itself
+        let descriptors = self.mmap_descriptor_count();
+        let present = |p: bool| if p { "yes" } else { "no" };
+        info!(
+            "Handoff: entry {:#X}, {} segments, {:#X} bytes copied, framebuffer {}, initrd {}, \
+             {} memory map descriptors",
+            entry as usize,
+            self.segment_count,
+            copied,
+            present(self.fb_base != 0),
+            present(self.initrd_base != 0),
+            descriptors
+        );
+    }
+
+    /// How many descriptors of the raw memory map are valid. The buffer is `mmap_cap` bytes, only
+    /// the first `mmap_len` of them were written by exit_boot_services.
+    fn mmap_descriptor_count(&self) -> usize {
+        match (self.mmap_len, self.mmap_desc_size) {
+            (Some(len), Some(size)) if size > 0 => len / size,
+            _ => 0,
+        }
+    }
+
+    /// Log every descriptor of the raw memory map, stepping by the firmware's descriptor size.
+    /// Doesn't allocate, so it can run after exit_boot_services.
+    pub fn dump_mmap(&self) {
+        let (buf, desc_size) = match (self.mmap_buf, self.mmap_desc_size) {
+            (Some(buf), Some(size)) if size > 0 => (buf, size),
+            _ => {
+                info!("No memory map in the eboot table to dump");
+                return;
+            }
+        };
+
+        let count = self.mmap_descriptor_count();
+        info!("Memory map, {} descriptors:", count);
+        for offset in (0..count).map(|i| i * desc_size) {
+            let d = unsafe { &*(buf.add(offset) as *const MemoryDescriptor) };
+            info!(
+                "  {:?} @ {:#014X}, {} pages, {:?}",
+                d.ty, d.phys_start, d.page_count, d.att
+            );
+        }
+    }
+
+    pub fn set_framebuffer(&mut self, fb: &gop::FramebufferInfo) {
+        self.fb_base = fb.base;
+        self.fb_size = fb.size;
+        self.fb_width = fb.width;
+        self.fb_height = fb.height;
+        self.fb_stride = fb.stride;
+        self.fb_format = fb.format as u32;
+    }
+
+    pub fn set_video_modes(&mut self, modes: &[gop::VideoMode]) {
+        self.video_modes_ptr = modes.as_ptr();
+        self.video_modes_count = modes.len();
+    }
+
+    pub fn set_segments(&mut self, segments: &'static [KernelSegment]) {
+        self.segments = segments.as_ptr();
+        self.segment_count = segments.len();
+    }
+
+    pub fn set_tls(&mut self, tls: &TlsTemplate) {
+        self.tls_vaddr = tls.vaddr;
+        self.tls_filesz = tls.filesz;
+        self.tls_memsz = tls.memsz;
+        self.tls_align = tls.align;
+    }
+
+    pub fn set_loader_image(&mut self, start: u64, end: u64) {
+        self.loader_image_base = start;
+        self.loader_image_size = end - start;
+    }
+
+    pub fn set_initrd(&mut self, base: u64, len: usize) {
+        self.initrd_base = base;
+        self.initrd_len = len;
+    }
+
+    pub fn set_runtime_services(&mut self, rs: *const RuntimeServices) {
+        if rs.is_null() {
+            warn!("Runtime services table pointer is null, not reporting it");
+            return;
+        }
+        self.runtime_services = rs;
+    }
+
+    pub fn set_dtb(&mut self, base: u64, len: usize) {
+        self.dtb_ptr = base;
+        self.dtb_len = len;
+    }
+
+    pub fn set_processor_count(&mut self, count: &ProcessorCount) {
+        self.processor_count = count.total as u64;
+        self.enabled_processor_count = count.enabled as u64;
+    }
+
+    pub fn set_firmware(&mut self, vendor: Option<*const u8>, major: u16, minor: u16) {
+        self.firmware_vendor = vendor.unwrap_or(core::ptr::null());
+        self.uefi_revision = (major as u32) << 16 | minor as u32;
+    }
+
+    pub fn set_kaslr_offset(&mut self, offset: u64) {
+        self.kaslr_offset = offset;
+    }
+
+    pub fn set_rng_seed(&mut self, seed: &[u8]) {
+        self.rng_seed_ptr = seed.as_ptr();
+        self.rng_seed_len = seed.len();
+    }
+
+    pub fn set_config(&mut self, ptr: *const u8, len: usize) {
+        self.config_ptr = ptr;
+        self.config_len = len;
+    }
+
+    pub fn set_modules(&mut self, modules: &[BootModule]) {
+        self.modules_ptr = modules.as_ptr();
+        self.modules_count = modules.len();
+    }
+
+    pub fn set_cmdline(&mut self, ptr: *const u8, len: usize) {
+        self.cmdline_ptr = ptr;
+        self.cmdline_len = len;
+    }
+
+    pub fn set_sorted_mmap(&mut self, map: &mmap::SortedMap) {
+        self.sorted_mmap = map.as_ptr();
+        self.sorted_mmap_len = map.len();
+    }
+
+    pub fn set_boot_services(&mut self, st: SystemTable<Boot>, image_handle: Handle) {
+        self.boot_sys_table = Some(st);
+        self.image_handle = Some(image_handle);
+    }
+
+    pub fn set_smbios(&mut self, entry: *const c_void) {
+        self.smbios_entry = entry as u64;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_page_table(&mut self, pml4: u64) {
+        self.pml4 = pml4;
+    }
+
+    pub fn set_stack(&mut self, base: u64, size: usize) {
+        self.stack_base = base;
+        self.stack_size = size;
     }
 }
 
 #[no_mangle]
-pub extern "win64" fn efi_main(
+pub extern "efiapi" fn efi_main(
     efi_image_handle: uefi::Handle,
     mut sys_table: SystemTable<Boot>,
 ) -> ! {
-    // Initialize logging, memory allocation and uefi services
-    uefi_services::init(&mut sys_table).expect_success("Failed to init UEFI Utilities!");
+    // Initialize logging (console + serial) and memory allocation
+    logger::init(&mut sys_table).expect_success("Failed to init UEFI Utilities!");
+    #[cfg(feature = "profile")]
+    profile::init(sys_table.boot_services());
+
+    match boot(efi_image_handle, sys_table) {
+        Ok(never) => match never {},
+        Err(e) => {
+            // serial output is polled, by the time error! returns it has all been sent
+            error!("boot failed: {}", e);
+            panic::halt()
+        }
+    }
+}
 
+/// Find, load and enter the kernel. Only returns if something went wrong before the jump.
+fn boot(
+    efi_image_handle: uefi::Handle,
+    mut sys_table: SystemTable<Boot>,
+) -> Result<core::convert::Infallible, BootError> {
     let out = sys_table.stdout();
 
     out.set_color(
         proto::console::text::Color::Green,
         proto::console::text::Color::Black,
     )
-    .expect_success("Failed to set console colors");
-    out.clear().expect_success("Failed to clear console");
+    .map_err(|e| BootError::Console(e.status()))?
+    .log();
+    out.clear()
+        .map_err(|e| BootError::Console(e.status()))?
+        .log();
+
+    info!("{}", LOADER_VERSION.trim_end_matches('\0'));
 
-    // scoped to help clean up after all of this stuff goes out of scope
+    // output firmware-vendor (CStr16 to Rust string), kept for the kernel
+    // max size of 32 characters
+    let mut firmware_vendor = arrayvec::ArrayString::<32>::new();
+    if sys_table
+        .firmware_vendor()
+        .as_str_in_buf(&mut firmware_vendor)
+        .is_err()
     {
-        // output firmware-vendor (CStr16 to Rust string)
-        // max size of 32 characters
-        let mut buf = arrayvec::ArrayString::<32>::new();
-        sys_table.firmware_vendor().as_str_in_buf(&mut buf).unwrap();
-        info!("Firmware Vendor: {}", buf.as_str());
+        warn!("Firmware vendor is longer than 32 bytes, truncating it");
     }
+    info!("Firmware Vendor: {}", firmware_vendor.as_str());
 
-    // more scoping to help keep the scope clean
+    let rev = sys_table.uefi_revision();
+    let (major, minor) = (rev.major(), rev.minor());
+    // scoped to help keep the scope clean
     {
-        let rev = sys_table.uefi_revision();
-        let (major, minor) = (rev.major(), rev.minor());
         let buf = format!("UEFI {}.{}", major, minor / 10);
         info!("{}", buf);
 
-        assert!(major >= 2, "Running on an old, unsupported version of UEFI");
-        assert!(
-            minor >= 30,
-            "Old version of UEFI 2, some features might not be available."
-        );
+        if major < 2 || (major == 2 && minor < 30) {
+            return Err(BootError::UnsupportedUefi { major, minor });
+        }
     }
 
     //memory_map(&sys_table.boot_services());
-    let kernel_image_handle =
-        match get_kernel_image_handle(sys_table.boot_services(), efi_image_handle) {
-            Some(t) => t,
-            None => panic!("unable to get kernel image file handle"),
-        };
+    // the boot volume keeps its protocol open through the borrow of boot services, do that
+    // through a copy of the table so sys_table itself stays free for the menu to borrow mutably
+    let fs_table = unsafe { sys_table.unsafe_clone() };
+    let boot_once = bootonce::read(sys_table.runtime_services());
+    let mut boot_volume = get_kernel_image_handle(
+        fs_table.boot_services(),
+        efi_image_handle,
+        boot_once.as_deref(),
+    )?;
+    if boot_once.is_some() {
+        bootonce::clear(sys_table.runtime_services());
+    }
+    #[cfg(feature = "profile")]
+    profile::mark(profile::Mark::VolumeFound);
+    log::set_max_level(boot_volume.config.log_level);
+    if let Some(e) = boot_volume.config.error {
+        return Err(BootError::Config(e));
+    }
 
-    let kernel_entry = load_kernel_image(kernel_image_handle, sys_table.boot_services());
-    info!("Using {:#?} as entry point", &kernel_entry);
-
-    // Build a buffer big enough to handle the memory map
-    // TODO: this is aligned by chance because of how the uefi-rs allocator works
-    // it would be nice to get rid of heap allocations with arrayvec on the stack, but there isn't a good way to
-    // "set" the allignment of stuff allocated on the stack.
-    let mut mmap_buf = {
-        let mmap_size = sys_table.boot_services().memory_map_size();
-        let vec_size = mmap_size.map_size + (mmap_size.map_size as f32 * 0.125) as usize;
-        create_vec_buf(vec_size)
+    // a firmware update takes the place of this boot, this returns if there is none to submit
+    if let Some(path) = boot_volume.config.capsule.as_ref() {
+        capsule::apply(&sys_table, &mut boot_volume.root, path);
+    }
+
+    if boot_volume.config.timeout > 0
+        && menu::wait_for_key(&mut sys_table, boot_volume.config.timeout)
+    {
+        if let Some(name) = menu::select_kernel(
+            &mut sys_table,
+            &mut boot_volume.root,
+            &boot_volume.config.kernel,
+        ) {
+            boot_volume.kernel = KernelSource::File(
+                find_file(&mut boot_volume.root, &name)
+                    .map_err(|error| KernelLoadError::File { name, error })?,
+            );
+            boot_volume.config.kernel = name;
+        }
+    }
+
+    // set up before loading so the progress bar can be drawn while the kernel is read
+    let framebuffer = gop::init_framebuffer(sys_table.boot_services()).map_err(BootError::Gop)?;
+    let video_modes = gop::list_modes(sys_table.boot_services()).map_err(BootError::Gop)?;
+    let progress = match &framebuffer {
+        Some(fb) if boot_volume.config.progress => gop::ProgressBar::new(
+            fb,
+            boot_volume.config.progress_fg,
+            boot_volume.config.progress_bg,
+        ),
+        _ => None,
+    };
+    if let Some(bar) = &progress {
+        bar.advance(gop::Stage::FoundKernel);
+    }
+
+    #[cfg(feature = "profile")]
+    profile::mark(profile::Mark::LoadStarted);
+    let kernel_digest = read_kernel_digest(&mut boot_volume.root, &boot_volume.config.kernel)?;
+
+    let loader_image = loader_image_range(sys_table.boot_services(), efi_image_handle);
+    let kernel = load_kernel_image(
+        boot_volume.kernel,
+        &boot_volume.config.kernel,
+        sys_table.boot_services(),
+        kernel_digest.as_ref(),
+        &boot_volume.config,
+        progress.as_ref(),
+        loader_image,
+    )?;
+    info!("Using {:#?} as entry point", &kernel.entry);
+
+    // the config table is only reachable through boot services, grab what the kernel needs now
+    let acpi_rsdp = acpi::find_rsdp(sys_table.config_table()).or_else(|| {
+        // low memory is identity mapped by firmware now, it may not be once the kernel runs
+        boot_volume
+            .config
+            .acpi_scan
+            .then(acpi::scan_legacy_rsdp)
+            .flatten()
+    });
+    let smbios_entry = smbios::find_entry_point(sys_table.config_table());
+    let processor_count = mp::processor_count(sys_table.boot_services());
+    let rng_seed = rng::stage_seed(sys_table.boot_services());
+
+    let initrd = load_initrd(
+        &mut boot_volume.root,
+        sys_table.boot_services(),
+        &boot_volume.config.initrd,
+    )?;
+    let dtb = load_dtb(
+        &mut boot_volume.root,
+        sys_table.boot_services(),
+        &boot_volume.config.dtb,
+    )?;
+    let modules = load_modules(
+        &mut boot_volume.root,
+        sys_table.boot_services(),
+        &boot_volume.config.modules,
+    )?;
+    let cmdline = boot_volume
+        .config
+        .cmdline
+        .as_ref()
+        .map(|c| stage_cmdline(sys_table.boot_services(), c))
+        .transpose()?;
+    let firmware_vendor = (!firmware_vendor.is_empty())
+        .then(|| stage_bytes(sys_table.boot_services(), firmware_vendor.as_bytes()))
+        .transpose()?;
+    // passed on as is, so the kernel can read keys of its own from it
+    let raw_config = boot_volume
+        .config
+        .raw
+        .as_ref()
+        .map(|data| stage_bytes(sys_table.boot_services(), data).map(|ptr| (ptr, data.len())))
+        .transpose()?;
+
+    // the firmware only gives us an identity map, kernels linked elsewhere need their own mappings.
+    // With boot services left running the kernel is entered on the firmware's tables instead, so
+    // they stay in charge of memory until it exits them itself.
+    #[cfg(target_arch = "x86_64")]
+    let page_tables = if boot_volume.config.handoff == Handoff::BootServices {
+        if let Some(seg) = kernel.segments.iter().find(|seg| seg.vaddr != seg.paddr) {
+            return Err(BootError::NotIdentityMapped {
+                vaddr: seg.vaddr,
+                paddr: seg.paddr,
+            });
+        }
+        None
+    } else {
+        Some(
+            build_page_tables(
+                sys_table.boot_services(),
+                &kernel,
+                framebuffer.as_ref(),
+                boot_volume.config.runtime_virtual,
+            )
+            .map_err(BootError::PageTables)?,
+        )
     };
 
+    let (stack_base, stack_size) =
+        allocate_kernel_stack(sys_table.boot_services(), boot_volume.config.stack_size)?;
+
+    // reserved before the raw map buffer so that allocation is accounted for in its size
+    let mut sorted_mmap =
+        mmap::SortedMap::reserve(sys_table.boot_services()).map_err(|status| BootError::Alloc {
+            what: "sorted memory map",
+            status,
+        })?;
+
+    let mmap_size = sys_table.boot_services().memory_map_size();
+    let mut mmap_buf = stage_mmap_buf(sys_table.boot_services())?;
+
     // transmute to function pointer from entry point
     let kmain: extern "C" fn(eboot: *mut EBootTable) =
-        unsafe { core::mem::transmute(kernel_entry) };
+        unsafe { core::mem::transmute(kernel.entry) };
     // allocate memory for eboot table before exiting boot services.
-    let eboot = unsafe { EBootTable::new() };
+    let eboot = EBootTable::new(sys_table.boot_services())?;
+    // without a GOP the framebuffer fields stay zeroed
+    if let Some(fb) = &framebuffer {
+        eboot.set_framebuffer(fb);
+    }
+    eboot.set_video_modes(video_modes);
+    let segments = stage_segments(sys_table.boot_services(), &kernel.segments)?;
+    eboot.set_segments(segments);
+    if let Some(tls) = &kernel.tls {
+        eboot.set_tls(tls);
+    }
+    if let Some((start, end)) = loader_image {
+        eboot.set_loader_image(start, end);
+    }
+    if let Some((base, len)) = initrd {
+        eboot.set_initrd(base, len);
+    }
+    if let Some((base, len)) = dtb {
+        eboot.set_dtb(base, len);
+    }
+    if !modules.is_empty() {
+        eboot.set_modules(modules);
+    }
+    if let Some((ptr, len)) = cmdline {
+        eboot.set_cmdline(ptr, len);
+    }
+
+    eboot.set_firmware(firmware_vendor, major, minor);
+    eboot.set_kaslr_offset(kernel.kaslr_offset);
+
+    if let Some((ptr, len)) = raw_config {
+        eboot.set_config(ptr, len);
+    }
+
+    if let Some(entry) = smbios_entry {
+        eboot.set_smbios(entry);
+    }
+
+    if let Some(count) = processor_count {
+        eboot.set_processor_count(&count);
+    }
+
+    if let Some(seed) = rng_seed {
+        eboot.set_rng_seed(seed);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if let Some(page_tables) = &page_tables {
+        eboot.set_page_table(page_tables.pml4_addr());
+    }
+
+    eboot.set_stack(stack_base, stack_size);
+
+    // ExitBootServices disarms the watchdog as well, but a kernel entered with boot services
+    // running would otherwise be reset 5 minutes after the loader started
+    if boot_volume.config.watchdog == Watchdog::Off {
+        match sys_table
+            .boot_services()
+            .set_watchdog_timer(0, WATCHDOG_CODE, None)
+        {
+            Ok(_) => info!("Disabled firmware watchdog timer"),
+            Err(e) => warn!("Unable to disable watchdog timer: {:?}", e.status()),
+        }
+    }
+
+    if let Some(bar) = &progress {
+        bar.advance(gop::Stage::ExitingBootServices);
+    }
+
+    if boot_volume.config.handoff == Handoff::BootServices {
+        info!("Entering kernel with boot services active");
+        #[cfg(feature = "profile")]
+        profile::report();
+        eboot.set_boot_services(unsafe { sys_table.unsafe_clone() }, efi_image_handle);
+        eboot.set_runtime_services(sys_table.runtime_services());
+        eboot.log_summary(kernel.entry, kernel.copied);
+
+        unsafe {
+            enter_kernel(
+                kmain,
+                eboot,
+                stack_base + stack_size as u64,
+                boot_volume.config.entry_abi,
+            )
+        }
+    }
+
+    // the virtual map is filled in from the final memory map, when allocating is no longer allowed
+    let mut runtime_map: Option<Vec<MemoryDescriptor>> = boot_volume
+        .config
+        .runtime_virtual
+        .then(|| Vec::with_capacity(mmap_buf.len() / mmap_size.entry_size));
 
     info!("Exiting UEFI Boot services");
-    let rt_table = match sys_table.exit_boot_services(efi_image_handle, &mut mmap_buf) {
-        Ok(t) => {
-            let (rt, _) = t.log();
-            rt
+    #[cfg(feature = "profile")]
+    profile::mark(profile::Mark::ExitStarted);
+    let mut attempt = 1;
+    let (rt_table, descriptor_count) = loop {
+        // exit_boot_services consumes the table even on failure, keep our own copy for retries
+        let st = unsafe { sys_table.unsafe_clone() };
+        let result = st.exit_boot_services(efi_image_handle, mmap_buf).map(|t| {
+            let (rt, descriptors) = t.log();
+            let count = descriptors.len();
+            sorted_mmap.fill(descriptors.clone());
+            if let Some(map) = runtime_map.as_mut() {
+                collect_runtime_map(map, descriptors);
+            }
+            (rt, count)
+        });
+
+        match result {
+            Ok(exited) => break exited,
+            Err(e) if attempt < EXIT_BOOT_SERVICES_ATTEMPTS => {
+                warn!(
+                    "Failed to exit boot services: {:?}, refreshing memory map (attempt {}/{})",
+                    e.status(),
+                    attempt,
+                    EXIT_BOOT_SERVICES_ATTEMPTS
+                );
+
+                if e.status() == Status::BUFFER_TOO_SMALL {
+                    // the map outgrew the buffer before ExitBootServices was called, so
+                    // allocating a bigger one is still allowed
+                    let bs = sys_table.boot_services();
+                    let pages = (mmap_buf.len() + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+                    let _ = bs.free_pages(mmap_buf.as_ptr() as u64, pages);
+                    mmap_buf = stage_mmap_buf(bs)?;
+                } else {
+                    let _ = sys_table.boot_services().memory_map(mmap_buf);
+                }
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(BootError::ExitBootServices {
+                    attempts: attempt,
+                    status: e.status(),
+                })
+            }
+        }
+    };
+
+    // read before remapping, afterwards the table is only reachable at its virtual address
+    let runtime_services = unsafe { rt_table.runtime_services() } as *const RuntimeServices;
+    let (rt_table, runtime_services) = match runtime_map {
+        Some(mut map) => {
+            let st_virt = rt_table.get_current_system_table_addr() + RUNTIME_VIRT_OFFSET;
+            info!(
+                "Remapping {} runtime regions, system table @ {:#X}",
+                map.len(),
+                st_virt
+            );
+            // runtime services may be half converted by now and the console is gone, fail the
+            // boot so the error at least reaches the serial log
+            let rt_table = unsafe { rt_table.set_virtual_address_map(&mut map, st_virt) }
+                .map_err(|e| BootError::SetVirtualAddressMap(e.status()))?
+                .log();
+            let rs_virt = runtime_services as u64 + RUNTIME_VIRT_OFFSET;
+            (rt_table, rs_virt as *const RuntimeServices)
         }
-        Err(_) => todo!(),
+        None => (rt_table, runtime_services),
     };
+    #[cfg(feature = "profile")]
+    {
+        profile::mark(profile::Mark::BootServicesExited);
+        profile::report();
+    }
 
     // update eboot table with Runtime view of SystemTable and memory map buffer
+    eboot.set_sorted_mmap(&sorted_mmap);
+    eboot.update(
+        rt_table,
+        mmap_buf,
+        descriptor_count,
+        mmap_size.entry_size,
+        acpi_rsdp,
+    );
+    eboot.set_runtime_services(runtime_services);
+    if boot_volume.config.dump == Some(Dump::Mmap) {
+        eboot.dump_mmap();
+    }
+    eboot.log_summary(kernel.entry, kernel.copied);
+
+    // jump to kernel entry point, the firmware stack may be reclaimed so switch off it first.
+    // CR3 is switched in the same asm block, see enter_kernel
     unsafe {
+        enter_kernel(
+            kmain,
+            eboot,
+            stack_base + stack_size as u64,
+            boot_volume.config.entry_abi,
+        )
+    }
+}
+
+/// Copy the EFI_MEMORY_RUNTIME descriptors into `map` with their virtual addresses assigned.
+/// `map` must not grow, since boot services have already been exited.
+fn collect_runtime_map<'a>(
+    map: &mut Vec<MemoryDescriptor>,
+    descriptors: impl Iterator<Item = &'a MemoryDescriptor>,
+) {
+    for d in descriptors.filter(|d| d.att.contains(MemoryAttribute::RUNTIME)) {
+        if map.len() == map.capacity() {
+            warn!("Too many runtime regions, not all of them will be remapped");
+            break;
+        }
+        let mut d = *d;
+        d.virt_start = d.phys_start + RUNTIME_VIRT_OFFSET;
+        map.push(d);
+    }
+}
+
+/// Switch to the kernel stack at `stack_top` and call `entry` with `eboot`
+///
+/// With `EntryAbi::Efi` the kernel is called as `extern "C"`, which on this target is the
+/// Microsoft x64 convention: the table goes in RCX and the callee expects 32 bytes of shadow space
+/// above the return address. With `EntryAbi::SysV` it gets `EBOOT_MAGIC` in RAX and the table in
+/// RDI instead, with RSP 16 byte aligned before the call and no shadow space. Either way RBP is
+/// zero and the kernel may return, see `kernel_returned`.
+///
+/// This is also the trampoline onto the loader's page tables: the PML4 in `eboot.pml4` is loaded
+/// as the first instruction, so no compiled code runs between the CR3 switch and the call into a
+/// higher half `entry`. The block itself, the new stack and the table live in loader or
+/// boot services memory, which is identity mapped by the firmware's tables and by ours, so the
+/// instruction after `mov cr3` is fetched from the same address either way. `entry` is a full
+/// 64 bit register operand, so a near call reaches a canonical high address without a far jump.
+/// A `pml4` of 0 skips the switch and enters on the firmware's tables, for `handoff =
+/// boot-services`.
+#[cfg(target_arch = "x86_64")]
+unsafe fn enter_kernel(
+    entry: extern "C" fn(eboot: *mut EBootTable),
+    eboot: *mut EBootTable,
+    stack_top: u64,
+    abi: EntryAbi,
+) -> ! {
+    let pml4 = (*eboot).pml4;
+
+    if abi == EntryAbi::SysV {
+        asm!(
+            "test {pml4}, {pml4}",
+            "jz 2f",
+            "mov cr3, {pml4}",
+            "2:",
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "call {entry}",
+            // kernel_returned is Microsoft x64 like the rest of the loader, give it shadow space
+            "sub rsp, 32",
+            "mov rcx, r12",
+            "call r13",
+            pml4 = in(reg) pml4,
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            in("rax") EBOOT_MAGIC,
+            in("rdi") eboot,
+            in("r12") eboot,
+            in("r13") kernel_returned,
+            options(noreturn)
+        );
+    }
+
+    asm!(
+        "test {pml4}, {pml4}",
+        "jz 2f",
+        "mov cr3, {pml4}",
+        "2:",
+        "mov rsp, {stack}",
+        "xor ebp, ebp",
+        "sub rsp, 32",
+        "call {entry}",
+        // there is nowhere to return to, the loader's stack is gone. R12 and R13 are callee
+        // saved, so they still hold the table and the handler if the kernel does come back.
+        "mov rcx, r12",
+        "call r13",
+        pml4 = in(reg) pml4,
+        stack = in(reg) stack_top,
+        entry = in(reg) entry,
+        in("rcx") eboot,
+        in("r12") eboot,
+        in("r13") kernel_returned,
+        options(noreturn)
+    );
+}
+
+/// Switch to the kernel stack at `stack_top` and call `entry` with `eboot`, passed in X0 as
+/// AAPCS64 expects. There is only the one convention here, so `_abi` is ignored.
+#[cfg(target_arch = "aarch64")]
+unsafe fn enter_kernel(
+    entry: extern "C" fn(eboot: *mut EBootTable),
+    eboot: *mut EBootTable,
+    stack_top: u64,
+    _abi: EntryAbi,
+) -> ! {
+    asm!(
+        "mov sp, {stack}",
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "blr {entry}",
+        // there is nowhere to return to, the loader's stack is gone. X20 and X21 are callee
+        // saved, so they still hold the table and the handler if the kernel does come back.
+        "mov x0, x20",
+        "blr x21",
+        stack = in(reg) stack_top,
+        entry = in(reg) entry,
+        in("x0") eboot,
+        in("x20") eboot,
+        in("x21") kernel_returned,
+        options(noreturn)
+    );
+}
+
+/// Called on the kernel stack if the kernel entry point ever returns, which a kernel in early
+/// bring-up easily does
+extern "C" fn kernel_returned(eboot: *mut EBootTable) -> ! {
+    // serial output is polled, by the time error! returns it has all been sent
+    error!(
+        "kernel entry returned unexpectedly, eboot table @ {:p}",
         eboot
-            .as_mut()
-            .expect("error creating eboot table")
-            .update(rt_table, mmap_buf)
+    );
+    panic::halt()
+}
+
+/// Reserve the stack the kernel is entered on, returning its base and page rounded size
+fn allocate_kernel_stack(bs: &BootServices, size: usize) -> Result<(u64, usize), BootError> {
+    let pages = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::KERNEL_STACK, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "kernel stack",
+            status: e.status(),
+        })?
+        .log();
+
+    let size = pages * PAGE_SIZE as usize;
+    info!("Kernel stack @ {:#X} ({} bytes)", base, size);
+    Ok((base, size))
+}
+
+/// Build page tables that identity map physical memory and map each kernel segment at its
+/// virtual address
+#[cfg(target_arch = "x86_64")]
+fn build_page_tables(
+    bs: &BootServices,
+    kernel: &LoadedKernel,
+    framebuffer: Option<&gop::FramebufferInfo>,
+    map_runtime: bool,
+) -> Result<paging::PageTables, paging::Error> {
+    let mut page_tables = paging::PageTables::new(bs)?;
+
+    // the framebuffer is MMIO and may not be described by the memory map
+    let fb_end = framebuffer.map_or(0, |fb| fb.base + fb.size as u64);
+    let identity_end = paging::physical_memory_end(bs)?.max(fb_end);
+    page_tables.identity_map(bs, identity_end)?;
+
+    for seg in &kernel.segments {
+        if seg.vaddr == seg.paddr {
+            continue;
+        }
+        info!(
+            "Mapping kernel segment {:#X} -> {:#X} ({:#X} bytes)",
+            seg.vaddr, seg.paddr, seg.size
+        );
+        let writable = seg.flags & program_header::PF_W != 0;
+        page_tables.map(bs, seg.vaddr, seg.paddr, seg.size, writable)?;
+    }
+
+    if map_runtime {
+        paging::map_runtime_services(&mut page_tables, bs, RUNTIME_VIRT_OFFSET)?;
+    }
+
+    Ok(page_tables)
+}
+
+/// The volume the kernel was found on, along with the config that was read from it
+struct BootVolume<'a> {
+    root: Directory,
+    kernel: KernelSource,
+    config: BootConfig,
+    /// Kept open for as long as files on the volume are, declared last so it is closed after them
+    _fs: ScopedProtocol<'a, SimpleFileSystem>,
+}
+
+/// Where the kernel image comes from
+enum KernelSource {
+    /// A file on the boot volume, not read yet
+    File(FileHandle),
+    /// Raw blocks already read off the boot volume's block device, see `block`
+    Blocks(Vec<u8>),
+}
+
+/// Locations searched for the kernel, for reporting when it couldn't be found anywhere
+#[derive(Debug)]
+struct KernelNotFound {
+    /// Volume index and path tried, `None` if the volume itself couldn't be opened
+    tried: Vec<(usize, Option<arrayvec::ArrayString<64>>)>,
+}
+
+impl core::fmt::Display for KernelNotFound {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "kernel image not found, tried:")?;
+        for (volume, path) in &self.tried {
+            match path {
+                Some(p) => write!(f, "\n  volume {}: {}", volume, p)?,
+                None => write!(f, "\n  volume {}: unable to open volume", volume)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Search every SimpleFileSystem volume for the kernel, starting with the one the loader was
+/// started from, or only those labelled with the `volume` from the loader's own config if any are.
+/// On each volume the path from that volume's config is tried first, then
+/// `config::FALLBACK_KERNEL_PATHS` in order. A `boot_once` path is tried
+/// before all of them.
+fn get_kernel_image_handle<'a>(
+    bt: &'a BootServices,
+    efi_image_handle: uefi::Handle,
+    boot_once: Option<&str>,
+) -> Result<BootVolume<'a>, BootError> {
+    let handles = locate_filesystems(bt)?;
+    info!("Found {} valid EFI FileSystem handles", handles.len());
+
+    let mut not_found = KernelNotFound { tried: Vec::new() };
+
+    // the loader's own volume goes first, so the config next to it wins over a stale one on
+    // another disk. The rest follow in the order firmware reported them.
+    let loader_device = loader_device(bt, efi_image_handle);
+    let mut indices: Vec<usize> = (0..handles.len()).collect();
+    if let Some(own) = loader_device.and_then(|d| handles.iter().position(|h| same_handle(*h, d))) {
+        debug!("Loader was started from FileSystem volume {}", own);
+        indices.remove(own);
+        indices.insert(0, own);
+    }
+
+    let wanted_label = loader_device.and_then(|d| wanted_volume_label(bt, d, efi_image_handle));
+    if let Some(label) = wanted_label {
+        let labelled: Vec<usize> = indices
+            .iter()
+            .copied()
+            .filter(|&i| {
+                open_volume(bt, handles[i], efi_image_handle)
+                    .and_then(|(_fs, mut dir)| volume_label(&mut dir))
+                    .map_or(false, |l| l.eq_ignore_ascii_case(&label))
+            })
+            .collect();
+        if labelled.is_empty() {
+            warn!("No volume labelled {}, searching all volumes", label);
+        } else {
+            info!("Searching {} volume(s) labelled {}", labelled.len(), label);
+            indices = labelled;
+        }
+    }
+
+    // the kernel may live on any of the volumes, so check each one in turn. Volumes it isn't on
+    // have their root directory and protocol closed again before moving on, at the end of each
+    // iteration, as some firmware limits how many can be open at once.
+    for index in indices {
+        let (fs, mut dir) = match open_volume(bt, handles[index], efi_image_handle) {
+            Some(v) => v,
+            None => {
+                debug!("Skipping FileSystem volume {}", index);
+                not_found.tried.push((index, None));
+                continue;
+            }
+        };
+
+        let mut config = read_boot_config(&mut dir, index);
+
+        if let (Some(lba), Some(count)) = (config.block_lba, config.block_count) {
+            match block::read_kernel(bt, handles[index], efi_image_handle, lba, count) {
+                Ok(data) => {
+                    info!(
+                        "Read kernel image from {} blocks @ LBA {} of FileSystem volume {}",
+                        count, lba, index
+                    );
+                    return Ok(BootVolume {
+                        root: dir,
+                        kernel: KernelSource::Blocks(data),
+                        config,
+                        _fs: fs,
+                    });
+                }
+                Err(e) => warn!(
+                    "Unable to read kernel from LBA {} of FileSystem volume {}: {}, looking \
+                     for a file instead",
+                    lba, index, e
+                ),
+            }
+        }
+
+        let mut candidates = ArrayVec::<arrayvec::ArrayString<64>, 4>::new();
+        if let Some(path) = boot_once {
+            candidates.push(arrayvec::ArrayString::from(path).unwrap());
+        }
+        if !candidates.contains(&config.kernel) {
+            candidates.push(config.kernel);
+        }
+        for path in config::FALLBACK_KERNEL_PATHS {
+            let path = arrayvec::ArrayString::from(path).unwrap();
+            if !candidates.contains(&path) {
+                candidates.push(path);
+            }
+        }
+
+        for path in candidates {
+            not_found.tried.push((index, Some(path)));
+
+            let path = if path.contains('*') {
+                match resolve_wildcard(&mut dir, &path) {
+                    Some(p) => p,
+                    None => continue,
+                }
+            } else {
+                path
+            };
+
+            let kernel_file = match open_path(&mut dir, &path) {
+                Ok(f) => f,
+                Err(FileError::NotFound) => continue,
+                Err(e) => {
+                    warn!("Kernel image {} on FileSystem volume {} {}", path, index, e);
+                    continue;
+                }
+            };
+            info!(
+                "Found kernel image {} on FileSystem volume {} ({})",
+                path,
+                index,
+                volume_label(&mut dir).as_deref().unwrap_or("no label")
+            );
+
+            // later lookups next to the kernel, like its digest, go by this name
+            config.kernel = path;
+            return Ok(BootVolume {
+                root: dir,
+                kernel: KernelSource::File(kernel_file),
+                config,
+                _fs: fs,
+            });
+        }
+    }
+
+    Err(not_found.into())
+}
+
+/// Handle of the device the loader image was read from, from its LoadedImage protocol
+fn loader_device(bt: &BootServices, image: Handle) -> Option<Handle> {
+    let params = OpenProtocolParams {
+        handle: image,
+        agent: image,
+        controller: None,
     };
-    // jump to kernel entry point
-    (kmain)(eboot);
+    let loaded_image: ScopedProtocol<LoadedImage> =
+        match bt.open_protocol(params, OpenProtocolAttributes::GetProtocol) {
+            Ok(sp) => sp.log(),
+            Err(e) => {
+                warn!("Unable to open LoadedImage protocol: {:?}", e.status());
+                return None;
+            }
+        };
+    Some(unsafe { &*loaded_image.interface.get() }.device())
+}
 
-    panic!();
+/// Handle doesn't implement PartialEq, compare the pointers it wraps
+fn same_handle(a: Handle, b: Handle) -> bool {
+    // Handle is repr(transparent) over a non-null pointer
+    unsafe { core::mem::transmute::<Handle, *mut c_void>(a) == core::mem::transmute(b) }
 }
 
-// TODO: this function is getting large, it looks like it might be time to break it up a bit.
-fn get_kernel_image_handle(
+/// The `volume` set in the config on the loader's own volume `device`, if any
+fn wanted_volume_label(
     bt: &BootServices,
-    efi_image_handle: uefi::Handle,
-) -> Option<FileHandle> {
+    device: Handle,
+    image: Handle,
+) -> Option<arrayvec::ArrayString<32>> {
+    let (_fs, mut dir) = open_volume(bt, device, image)?;
+    match load_file(&mut dir, config::CONFIG_FILE_NAME) {
+        Ok(data) => BootConfig::parse(&data).volume,
+        Err(_) => None,
+    }
+}
+
+/// The FAT label of the volume `root` is the root directory of, None if it has no label
+fn volume_label(root: &mut Directory) -> Option<arrayvec::ArrayString<32>> {
+    // Must be alligned, so this is left as a heap allocation
+    let mut info_buf = create_vec_buf(128);
+    let info = match root.get_info::<FileSystemVolumeLabel>(&mut info_buf) {
+        Ok(info) => info.log(),
+        Err(e) => {
+            debug!("Unable to read volume label: {:?}", e.status());
+            return None;
+        }
+    };
+
+    let mut label = arrayvec::ArrayString::<32>::new();
+    info.volume_label().as_str_in_buf(&mut label).ok()?;
+    (!label.is_empty()).then(|| label)
+}
+
+/// Get the handles of every volume supporting the SimpleFileSystem protocol
+fn locate_filesystems(bt: &BootServices) -> Result<Vec<Handle>, BootError> {
     let proto_query = SearchType::from_proto::<SimpleFileSystem>();
 
-    let buf_size = bt
+    let handle_count = bt
         .locate_handle(proto_query, None)
-        .expect("Failed to get required handle buf size")
+        .map_err(|e| BootError::LocateFilesystems(e.status()))?
         .log();
 
-    // allocate enough stack space for 8 handles...might need more on some systems...
-    // TODO: check what a good value would be here, since it has to be const
-    let mut buf: ArrayVec<MaybeUninit<Handle>, 8> =
-        arrayvec::ArrayVec::<MaybeUninit<Handle>, 8>::new();
-    unsafe {
-        buf.set_len(buf_size);
-    }
+    // there is no upper bound on how many volumes firmware can report, so size the buffer from
+    // the first query instead of guessing
+    let mut buf: Vec<MaybeUninit<Handle>> = Vec::with_capacity(handle_count);
+    buf.resize_with(handle_count, MaybeUninit::uninit);
 
-    let _ = bt
+    let written = bt
         .locate_handle(proto_query, Some(&mut buf))
-        .expect("Failed to get result size for handle buffer")
+        .map_err(|e| BootError::LocateFilesystems(e.status()))?
         .log();
 
-    info!("Found {} valid EFI FileSystem handles", buf.len());
+    // only the handles that were actually written are initialized
+    buf.truncate(written);
+    Ok(buf.iter().map(|h| unsafe { h.assume_init() }).collect())
+}
 
+/// Open the SimpleFileSystem on `handle` and its root directory. The protocol is closed when the
+/// returned ScopedProtocol is dropped, which must not happen before the directory is.
+fn open_volume(
+    bt: &BootServices,
+    handle: Handle,
+    agent: Handle,
+) -> Option<(ScopedProtocol<'_, SimpleFileSystem>, Directory)> {
     let params = OpenProtocolParams {
-        handle: unsafe { buf[0].assume_init() },
-        agent: efi_image_handle,
+        handle,
+        agent,
         controller: None,
     };
 
     let proto_volume: ScopedProtocol<SimpleFileSystem> =
         match bt.open_protocol(params, OpenProtocolAttributes::GetProtocol) {
             Ok(sp) => sp.log(),
-            Err(e) => panic!("{:#?}", e),
+            Err(e) => {
+                // some handles are transient or held exclusively by another driver
+                debug!("Unable to open FileSystem protocol: {:?}", e.status());
+                return None;
+            }
         };
 
     let volume = match unsafe { proto_volume.interface.get().as_mut() } {
         Some(sfs) => sfs,
-        None => panic!("no filesystem found"),
+        None => {
+            debug!("FileSystem protocol interface is null");
+            return None;
+        }
     };
 
-    let mut dir = volume
-        .open_volume()
-        .expect("Unable to open FileSystem volume root dir")
-        .log();
+    match volume.open_volume() {
+        Ok(d) => Some((proto_volume, d.log())),
+        Err(e) => {
+            debug!("Unable to open FileSystem root dir: {:?}", e.status());
+            None
+        }
+    }
+}
 
-    // Must be alligned, so this is left as a heap allocation
-    let mut dir_buf = create_vec_buf(128);
+/// Open the regular file at `path` below `root`. Components may be separated by `/` or `\`,
+/// each directory along the way is opened in turn before the file itself is looked up.
+fn open_path(root: &mut Directory, path: &str) -> Result<FileHandle, FileError> {
+    in_parent_dir(root, path, |dir, name| Some(find_file(dir, name)))
+        .unwrap_or(Err(FileError::NotFound))
+}
 
-    let mut kernel_exists = false;
+/// Open each directory along `path` below `root` and call `f` with the last one and the final
+/// component of `path`
+fn in_parent_dir<R>(
+    root: &mut Directory,
+    path: &str,
+    f: impl FnOnce(&mut Directory, &str) -> Option<R>,
+) -> Option<R> {
+    let components: Vec<&str> = path
+        .split(|c| c == '/' || c == '\\')
+        .filter(|c| !c.is_empty())
+        .collect();
+    let (name, dirs) = components.split_last()?;
 
-    loop {
-        match dir.read_entry(&mut dir_buf) {
-            Ok(file_info) => {
-                match file_info.log() {
-                    Some(fi) => {
-                        info!("found {:?} name: {}", &fi.attribute(), &fi.file_name());
-
-                        if fi.attribute() == FileAttribute::ARCHIVE {
-                            let mut temp_name = arrayvec::ArrayString::<64>::new();
-                            let _ = &fi.file_name().as_str_in_buf(&mut temp_name);
-
-                            if temp_name.as_str() == EFI_KERNEL_NAME {
-                                kernel_exists = true;
-                            }
-                        }
-                    }
-                    None => {
-                        // No more entries to get, read_entry() returns None
-                        break;
-                    }
-                }
+    let mut subdir: Option<Directory> = None;
+    for component in dirs {
+        let parent = match subdir.as_mut() {
+            Some(d) => d,
+            None => &mut *root,
+        };
+
+        let handle = match parent.open(component, FileMode::Read, FileAttribute::empty()) {
+            Ok(h) => h.log(),
+            Err(e) => {
+                warn!(
+                    "Path component {} of {} not found: {:?}",
+                    component,
+                    path,
+                    e.status()
+                );
+                return None;
             }
-            Err(_size) => todo!(),
-        }
-    }
+        };
 
-    if kernel_exists {
-        info!("Found kernel image");
-        let kernel_file = dir
-            .open(
-                EFI_KERNEL_NAME,
-                proto::media::file::FileMode::Read,
-                FileAttribute::READ_ONLY,
-            )
-            .expect("Unable to open kernel image for reading")
+        match handle.into_type().map(|t| t.log()) {
+            Ok(FileType::Dir(d)) => subdir = Some(d),
+            Ok(FileType::Regular(_)) => {
+                warn!(
+                    "Path component {} of {} is not a directory",
+                    component, path
+                );
+                return None;
+            }
+            Err(e) => {
+                warn!("Unable to open {} of {}: {:?}", component, path, e.status());
+                return None;
+            }
+        }
+    }
+
+    match subdir.as_mut() {
+        Some(d) => f(d, name),
+        None => f(root, name),
+    }
+}
+
+/// Resolve a kernel path whose file name contains a `*`, like `\boot\KERNEL-*`, to the matching
+/// file with the highest version. Returns the path with the wildcard replaced.
+fn resolve_wildcard(root: &mut Directory, pattern: &str) -> Option<arrayvec::ArrayString<64>> {
+    let name = in_parent_dir(root, pattern, latest_match)?;
+
+    // keep the directory part of the pattern, only the file name was matched
+    let file_pattern_len = pattern
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .map_or(0, str::len);
+    let mut path = arrayvec::ArrayString::<64>::new();
+    path.try_push_str(&pattern[..pattern.len() - file_pattern_len])
+        .ok()?;
+    path.try_push_str(&name).ok()?;
+
+    info!("Kernel pattern {} matched {}", pattern, path);
+    Some(path)
+}
+
+/// Find the regular file in `dir` matching `pattern`, which has a single `*` standing in for a
+/// version. The highest version wins, see `fs::compare_versions`.
+fn latest_match(dir: &mut Directory, pattern: &str) -> Option<arrayvec::ArrayString<64>> {
+    let (prefix, suffix) = pattern.split_once('*')?;
+
+    if dir.reset_entry_readout().is_err() {
+        return None;
+    }
+    // Must be alligned, so this is left as a heap allocation
+    let mut dir_buf = create_vec_buf(128);
+
+    let mut best: Option<arrayvec::ArrayString<64>> = None;
+    while let Some((name, attribute)) = next_entry(dir, &mut dir_buf) {
+        if attribute.contains(FileAttribute::DIRECTORY) {
+            continue;
+        }
+        let version = match fs::pattern_version(&name, prefix, suffix) {
+            Some(version) => version,
+            None => continue,
+        };
+        debug!("Kernel pattern {} candidate {}", pattern, name);
+
+        let is_newer = best.as_ref().map_or(true, |b| {
+            let best_version = &b[prefix.len()..b.len() - suffix.len()];
+            fs::compare_versions(version, best_version) == core::cmp::Ordering::Greater
+        });
+        if is_newer {
+            best = Some(name);
+        }
+    }
+
+    best
+}
+
+/// Name and attributes of the next entry in `dir`, None once there are no more. `buf` is grown
+/// when an entry doesn't fit, FileInfo for a long file name easily needs more than 128 bytes.
+/// Entries whose names are longer than any the loader looks for are skipped.
+fn next_entry(
+    dir: &mut Directory,
+    buf: &mut Vec<u8>,
+) -> Option<(arrayvec::ArrayString<64>, FileAttribute)> {
+    loop {
+        let entry = fs::read_growing(buf, |buf| match dir.read_entry(buf) {
+            Ok(entry) => Ok(entry.log().map(|fi| {
+                let mut name = arrayvec::ArrayString::<64>::new();
+                let fits = fi.file_name().as_str_in_buf(&mut name).is_ok();
+                (fits.then(|| name), fi.attribute())
+            })),
+            // the entry is left unread and the size it needs is reported instead
+            Err(e) => Err(match *e.data() {
+                Some(size) => fs::ReadError::TooSmall(size),
+                None => fs::ReadError::Other(e.status()),
+            }),
+        });
+
+        match entry {
+            Ok(Some((Some(name), attribute))) => return Some((name, attribute)),
+            Ok(Some((None, _))) => debug!("Skipping directory entry with a name over 64 bytes"),
+            Ok(None) => return None,
+            Err(status) => {
+                warn!("Unable to read directory entry: {:?}", status);
+                return None;
+            }
+        }
+    }
+}
+
+/// Scan `dir` for a regular file called `name` and open it read only
+fn find_file(dir: &mut Directory, name: &str) -> Result<FileHandle, FileError> {
+    // start from the first entry in case the directory has been read before
+    dir.reset_entry_readout()
+        .map_err(|e| FileError::Io(e.status()))?
+        .log();
+
+    // Must be alligned, so this is left as a heap allocation
+    let mut dir_buf = create_vec_buf(128);
+
+    let mut file_exists = false;
+
+    while let Some((temp_name, attribute)) = next_entry(dir, &mut dir_buf) {
+        info!("found {:?} name: {}", &attribute, &temp_name);
+
+        if fs::is_named_file(&temp_name, attribute.bits(), name) {
+            file_exists = true;
+        }
+    }
+
+    if !file_exists {
+        return Err(FileError::NotFound);
+    }
+
+    let file = dir
+        .open(name, FileMode::Read, FileAttribute::READ_ONLY)
+        .map_err(|e| FileError::Io(e.status()))?
+        .log();
+    Ok(file)
+}
+
+/// Read and parse the boot config from the root of `dir`, the root of FileSystem volume `volume`,
+/// falling back to defaults if it is absent
+fn read_boot_config(dir: &mut Directory, volume: usize) -> BootConfig {
+    match load_file(dir, config::CONFIG_FILE_NAME) {
+        Ok(data) => {
+            info!(
+                "Using \\{} on FileSystem volume {} ({} bytes)",
+                config::CONFIG_FILE_NAME,
+                volume,
+                data.len()
+            );
+            let mut config = BootConfig::parse(&data);
+            config.raw = Some(data);
+            config
+        }
+        Err(FileError::NotFound) => {
+            info!(
+                "No {} on FileSystem volume {}, using defaults",
+                config::CONFIG_FILE_NAME,
+                volume
+            );
+            BootConfig::default()
+        }
+        Err(e) => {
+            warn!(
+                "{} on FileSystem volume {} {}, using defaults",
+                config::CONFIG_FILE_NAME,
+                volume,
+                e
+            );
+            BootConfig::default()
+        }
+    }
+}
+
+/// Load the initial ramdisk `name` from `dir` into reserved pages, returning its base and length.
+/// A missing initrd is not an error, the kernel just won't get one.
+fn load_initrd(
+    dir: &mut Directory,
+    bs: &BootServices,
+    name: &str,
+) -> Result<Option<(u64, usize)>, BootError> {
+    let data = match load_file(dir, name) {
+        Ok(data) => data,
+        Err(FileError::NotFound) => {
+            info!("No initrd {} found, continuing without one", name);
+            return Ok(None);
+        }
+        Err(FileError::IsDirectory) => {
+            warn!("initrd {} is a directory, ignoring", name);
+            return Ok(None);
+        }
+        Err(error) => {
+            return Err(BootError::Initrd {
+                name: arrayvec::ArrayString::from(name).unwrap_or_default(),
+                error,
+            })
+        }
+    };
+    // there would be nothing to allocate pages for
+    if data.is_empty() {
+        warn!("initrd {} is empty, continuing without one", name);
+        return Ok(None);
+    }
+    let initrd_size = data.len();
+
+    // pages rather than pool memory, so the initrd stays put and is visible in the memory map
+    let pages = (initrd_size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::INITRD, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "initrd",
+            status: e.status(),
+        })?
+        .log();
+
+    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), base as *mut u8, initrd_size) };
+
+    info!(
+        "Loaded initrd {} @ {:#X}, {} bytes ({} pages)",
+        name, base, initrd_size, pages
+    );
+
+    Ok(Some((base, initrd_size)))
+}
+
+/// Magic at the start of every flattened devicetree, big endian like the rest of the header
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+/// Size of the version 17 FDT header, the smallest totalsize a blob can have
+const FDT_HEADER_LEN: usize = 40;
+
+/// Load the devicetree blob `name` from `dir` into reserved pages, returning its base and length.
+/// Like the initrd it is optional, but one that is present has to be a well formed FDT.
+fn load_dtb(
+    dir: &mut Directory,
+    bs: &BootServices,
+    name: &str,
+) -> Result<Option<(u64, usize)>, BootError> {
+    let name = arrayvec::ArrayString::from(name).unwrap_or_default();
+    let data = match load_file(dir, &name) {
+        Ok(data) => data,
+        Err(FileError::NotFound) => {
+            debug!("No devicetree {} found, continuing without one", name);
+            return Ok(None);
+        }
+        Err(error) => return Err(BootError::Dtb { name, error }),
+    };
+    if data.is_empty() {
+        warn!("Devicetree {} is empty, continuing without one", name);
+        return Ok(None);
+    }
+
+    // the header starts with the magic followed by totalsize, both big endian u32s
+    let header_u32 = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    };
+    if header_u32(0) != Some(FDT_MAGIC) {
+        return Err(BootError::InvalidDtb {
+            name,
+            reason: "bad FDT magic",
+        });
+    }
+    let dtb_size = match header_u32(4) {
+        Some(size) if size as usize > data.len() => {
+            return Err(BootError::InvalidDtb {
+                name,
+                reason: "totalsize is larger than the file",
+            })
+        }
+        Some(size) if (size as usize) < FDT_HEADER_LEN => {
+            return Err(BootError::InvalidDtb {
+                name,
+                reason: "totalsize is smaller than the FDT header",
+            })
+        }
+        Some(size) => size as usize,
+        None => {
+            return Err(BootError::InvalidDtb {
+                name,
+                reason: "truncated FDT header",
+            })
+        }
+    };
+
+    let pages = (dtb_size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::DEVICE_TREE, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "devicetree",
+            status: e.status(),
+        })?
+        .log();
+
+    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), base as *mut u8, dtb_size) };
+
+    info!(
+        "Loaded devicetree {} @ {:#X}, {} bytes ({} pages)",
+        name, base, dtb_size, pages
+    );
+
+    Ok(Some((base, dtb_size)))
+}
+
+/// A file listed in `modules`, as reported to the kernel through `EBootTable::modules_ptr`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BootModule {
+    /// NUL terminated name of the module as written in the config
+    name_ptr: *const u8,
+    /// Physical address the module was loaded at
+    base: u64,
+    /// Size of the module in bytes
+    size: usize,
+}
+
+/// Load every module in `names` from `dir` into reserved pages. The descriptors and the names
+/// they point to are copied into `memtype::BOOT_INFO` pages so they outlive the loader.
+/// Unlike the initrd a missing or empty module is fatal, the kernel asked for it by name.
+fn load_modules(
+    dir: &mut Directory,
+    bs: &BootServices,
+    names: &[arrayvec::ArrayString<64>],
+) -> Result<&'static [BootModule], BootError> {
+    if names.is_empty() {
+        return Ok(&[]);
+    }
+
+    // descriptors first so they stay aligned, the NUL terminated names are packed after them
+    let table_size = names.len() * core::mem::size_of::<BootModule>();
+    let names_size: usize = names.iter().map(|n| n.len() + 1).sum();
+    let pages = (table_size + names_size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let storage = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "boot module table",
+            status: e.status(),
+        })?
+        .log() as *mut u8;
+    let table = storage as *mut BootModule;
+    let mut name_ptr = unsafe { storage.add(table_size) };
+
+    for (index, name) in names.iter().enumerate() {
+        let data =
+            load_file(dir, name).map_err(|error| BootError::Module { name: *name, error })?;
+        if data.is_empty() {
+            return Err(BootError::Module {
+                name: *name,
+                error: FileError::Empty,
+            });
+        }
+
+        let module_pages = (data.len() + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let base = bs
+            .allocate_pages(AllocateType::AnyPages, memtype::BOOT_MODULE, module_pages)
+            .map_err(|e| BootError::Alloc {
+                what: "boot module",
+                status: e.status(),
+            })?
             .log();
 
-        Some(kernel_file)
-    } else {
-        warn!("Unable to locate kernel image!");
-        None
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), base as *mut u8, data.len());
+            core::ptr::copy_nonoverlapping(name.as_ptr(), name_ptr, name.len());
+            name_ptr.add(name.len()).write(0);
+            table.add(index).write(BootModule {
+                name_ptr,
+                base,
+                size: data.len(),
+            });
+            name_ptr = name_ptr.add(name.len() + 1);
+        }
+
+        info!(
+            "Loaded boot module {} @ {:#X}, {} bytes ({} pages)",
+            name,
+            base,
+            data.len(),
+            module_pages
+        );
     }
+
+    Ok(unsafe { core::slice::from_raw_parts(table, names.len()) })
 }
 
-fn load_kernel_image(mut kernel_handle: FileHandle, bs: &BootServices) -> *const () {
-    let mut size_buf = create_vec_buf(4096);
+/// Copy the kernel command line into its own reserved page(s) as a NUL terminated string
+fn stage_cmdline(bs: &BootServices, cmdline: &str) -> Result<(*const u8, usize), BootError> {
+    let len = cmdline.len();
+    let pages = (len + 1 + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "kernel command line",
+            status: e.status(),
+        })?
+        .log() as *mut u8;
 
-    let kernel_size: usize = kernel_handle
-        .get_info::<FileInfo>(&mut size_buf)
-        .expect("error getting kernel image file info")
+    unsafe {
+        core::ptr::copy_nonoverlapping(cmdline.as_ptr(), base, len);
+        base.add(len).write(0);
+    }
+
+    info!("Kernel command line: {}", cmdline);
+    Ok((base, len))
+}
+
+/// Copy the loaded segment list into `memtype::BOOT_INFO` pages for the kernel
+fn stage_segments(
+    bs: &BootServices,
+    segments: &[KernelSegment],
+) -> Result<&'static [KernelSegment], BootError> {
+    let size = core::mem::size_of_val(segments);
+    let pages = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "kernel segment list",
+            status: e.status(),
+        })?
+        .log() as *mut KernelSegment;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(segments.as_ptr(), base, segments.len());
+        Ok(core::slice::from_raw_parts(base, segments.len()))
+    }
+}
+
+/// Copy `data` into reserved pages followed by a NUL, for things handed to the kernel whose
+/// loader copy lives on the stack or heap, which the kernel is free to reclaim
+fn stage_bytes(bs: &BootServices, data: &[u8]) -> Result<*const u8, BootError> {
+    let pages = (data.len() + 1 + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let base = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+        .map_err(|e| BootError::Alloc {
+            what: "boot info",
+            status: e.status(),
+        })?
+        .log() as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), base, data.len());
+        base.add(data.len()).write(0);
+    }
+    Ok(base)
+}
+
+/// Stall before the first retry of a failed read, doubled for every retry after it
+const READ_RETRY_STALL_US: usize = 100_000;
+
+/// How often a failed File::read is retried, and the boot services to stall with in between
+#[derive(Clone, Copy)]
+struct ReadRetry<'a> {
+    bs: &'a BootServices,
+    retries: usize,
+}
+
+/// File::read of `file` at the offsets `fs::fill` reads from.
+///
+/// Flaky media, USB sticks in particular, can fail a read with a transient DEVICE_ERROR. With
+/// `retry` set a failed read is tried again after a stall, from the same position.
+fn retrying_reader<'a>(
+    file: &'a mut RegularFile,
+    retry: Option<ReadRetry<'a>>,
+) -> impl FnMut(u64, &mut [u8]) -> Result<usize, Status> + 'a {
+    let mut failures = 0;
+    move |offset, rest| loop {
+        match file.read(rest) {
+            Ok(bytes) => return Ok(bytes.log()),
+            Err(e) => match retry {
+                Some(retry) if failures < retry.retries => {
+                    let stall = READ_RETRY_STALL_US << failures;
+                    failures += 1;
+                    warn!(
+                        "Read failed at byte {}: {:?}, retrying in {} ms ({}/{})",
+                        offset,
+                        e.status(),
+                        stall / 1000,
+                        failures,
+                        retry.retries
+                    );
+                    retry.bs.stall(stall);
+                    // the position after a failed read is unspecified
+                    file.set_position(offset).map_err(|e| e.status())?.log();
+                }
+                _ => return Err(e.status()),
+            },
+        }
+    }
+}
+
+/// Reasons a file could not be read into memory
+#[derive(Debug)]
+enum FileError {
+    /// No regular file with that name exists
+    NotFound,
+    /// The name refers to a directory
+    IsDirectory,
+    /// Firmware reported an error while querying or reading the file
+    Io(Status),
+    /// The file ended before as many bytes as its FileInfo reported could be read
+    ShortRead { expected: usize, read: usize },
+    /// The file has more data than its FileInfo reported
+    LongRead { expected: usize },
+    /// The file exists but has no data in it
+    Empty,
+}
+
+// worded to follow the file name, e.g. "KERNEL is a directory, expected a regular file"
+impl core::fmt::Display for FileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FileError::NotFound => write!(f, "not found"),
+            FileError::IsDirectory => write!(f, "is a directory, expected a regular file"),
+            FileError::Io(status) => write!(f, "could not be read: {:?}", status),
+            FileError::ShortRead { expected, read } => {
+                write!(f, "is truncated, read {} of {} bytes", read, expected)
+            }
+            FileError::LongRead { expected } => {
+                write!(f, "has more data than the {} bytes it reports", expected)
+            }
+            FileError::Empty => write!(f, "is empty"),
+        }
+    }
+}
+
+/// Read the whole of an opened file, checking the amount read against the size in its FileInfo
+fn read_file(mut handle: FileHandle, retry: Option<ReadRetry>) -> Result<Vec<u8>, FileError> {
+    let mut info_buf = create_vec_buf(4096);
+    let size: usize = handle
+        .get_info::<FileInfo>(&mut info_buf)
+        .map_err(|e| FileError::Io(e.status()))?
         .log()
         .file_size()
         .try_into()
         .unwrap();
 
-    let mut entry_point: usize = 0x0;
-
-    match kernel_handle.into_type() {
-        Ok(f) => match f.log() {
-            FileType::Regular(mut kern) => {
-                let mut kern_buf = create_vec_buf(kernel_size + 1);
-
-                let bytes = kern.read(&mut kern_buf);
-
-                match goblin::elf::Elf::parse(&kern_buf) {
-                    Ok(obj) => {
-                        info!(
-                            "Found ELF binary with an entry point @ 0x{:X}, loaded {} bytes",
-                            obj.header.e_entry,
-                            bytes.expect("error reading kernel from disk").log()
-                        );
-                        entry_point = obj
-                            .header
-                            .e_entry
-                            .try_into()
-                            .expect("unable to convert to platform native entry point");
-
-                        for ph in obj.program_headers {
-                            if ph.p_vaddr == 0x0 && ph.p_paddr == 0x0 {
-                                continue;
-                            }
-                            info!("Found ELF program header >\nELF Offset:\t{:#X}\nLoad address:\t{:#X} & {:#X}\nFile image size:\t{:#X} bytes\nSize in memory:\t{:#X} bytes",
-                                            ph.p_offset, ph.p_vaddr, ph.p_paddr, ph.p_filesz, ph.p_memsz
-                                        );
-
-                            unsafe {
-                                let src = kern_buf.as_slice();
-                                let src_ptr = (src.as_ptr() as usize) + (ph.p_offset as usize);
-                                info!("Copying program header from {:#X} to {:#X}, count: {:#X} bytes", &src_ptr, ph.p_vaddr, ph.p_filesz);
-                                bs.memmove(
-                                    ph.p_vaddr as *mut u8,
-                                    src_ptr as *const u8,
-                                    ph.p_filesz.try_into().expect("convertion failure"),
-                                );
-                            }
-                        }
-
-                        for s in obj.section_headers {
-                            let section_name = obj
-                                .shdr_strtab
-                                .get_at(s.sh_name)
-                                .expect("error parsing section name");
-                            if section_name.is_empty() {
-                                continue;
-                            }
-                            info!("Found ELF section header {}\t> {:#X} - {:#X}\t({} bytes)\tALIGN: {:#X}\tFLAGS: {:#X}", section_name, s.sh_addr, s.sh_addr + s.sh_size, s.sh_size, s.sh_addralign,s.sh_flags);
-                        }
-                    }
-                    Err(e) => error!("Error parsing ELF: {}", &e),
-                }
-            }
-            FileType::Dir(_) => todo!(),
+    let mut file = match handle
+        .into_type()
+        .map_err(|e| FileError::Io(e.status()))?
+        .log()
+    {
+        FileType::Regular(f) => f,
+        FileType::Dir(_) => return Err(FileError::IsDirectory),
+    };
+
+    fs::read_exact(size, retrying_reader(&mut file, retry)).map_err(|e| match e {
+        fs::SizeError::Io(status) => FileError::Io(status),
+        fs::SizeError::Short { read } => FileError::ShortRead {
+            expected: size,
+            read,
         },
-        Err(_) => todo!(),
+        fs::SizeError::Long => FileError::LongRead { expected: size },
+    })
+}
+
+/// Read the whole of the file at `path`, relative to `dir`
+fn load_file(dir: &mut Directory, path: &str) -> Result<Vec<u8>, FileError> {
+    let handle = open_path(dir, path)?;
+    read_file(handle, None)
+}
+
+/// Look for `<kernel>.sha256` next to the kernel and return the digest it contains.
+/// The file uses the same format as sha256sum output, only the leading hex digest is used.
+fn read_kernel_digest(
+    dir: &mut Directory,
+    kernel_name: &str,
+) -> Result<Option<sha256::Digest>, BootError> {
+    let mut name = arrayvec::ArrayString::<72>::new();
+    name.push_str(kernel_name);
+    name.push_str(".sha256");
+
+    // the digest sits next to the kernel, which may be in a subdirectory
+    let buf = match load_file(dir, &name) {
+        Ok(buf) => buf,
+        Err(FileError::NotFound) => {
+            info!("No {} found, skipping kernel verification", name);
+            return Ok(None);
+        }
+        Err(error) => return Err(BootError::Digest { name, error }),
     };
 
-    entry_point as *const ()
+    let digest = core::str::from_utf8(&buf)
+        .ok()
+        .and_then(|text| text.split_whitespace().next())
+        .and_then(sha256::parse_hex);
+
+    match digest {
+        Some(d) => {
+            info!("Verifying kernel image against {}", name);
+            Ok(Some(d))
+        }
+        None => Err(BootError::InvalidDigest { name }),
+    }
 }
 
-fn create_vec_buf(vec_size: usize) -> Vec<u8> {
-    // inform compiler that data is uninit and should not perform optimizations
-    let mut data = MaybeUninit::<Vec<u8>>::uninit();
+/// Reasons booting stopped short of entering the kernel, logged by `efi_main` before halting
+#[derive(Debug)]
+enum BootError {
+    /// No volume had a kernel image at any of the paths tried
+    KernelNotFound(KernelNotFound),
+    /// The kernel was found but couldn't be read, verified or loaded
+    KernelLoad(KernelLoadError),
+    /// The kernel's `.sha256` file exists but couldn't be read
+    Digest {
+        name: arrayvec::ArrayString<72>,
+        error: FileError,
+    },
+    /// The kernel's `.sha256` file doesn't start with a SHA-256 digest
+    InvalidDigest { name: arrayvec::ArrayString<72> },
+    /// The initrd exists but couldn't be read
+    Initrd {
+        name: arrayvec::ArrayString<64>,
+        error: FileError,
+    },
+    /// The devicetree blob exists but couldn't be read
+    Dtb {
+        name: arrayvec::ArrayString<64>,
+        error: FileError,
+    },
+    /// The devicetree blob isn't a valid FDT
+    InvalidDtb {
+        name: arrayvec::ArrayString<64>,
+        reason: &'static str,
+    },
+    /// The config on the kernel's volume asks for something the loader can't do
+    Config(config::ConfigError),
+    /// A module listed in the config couldn't be read
+    Module {
+        name: arrayvec::ArrayString<64>,
+        error: FileError,
+    },
+    /// The console couldn't be set up for the boot messages
+    Console(Status),
+    /// The firmware implements an older UEFI revision than the loader needs, 2.3
+    UnsupportedUefi { major: u16, minor: u16 },
+    /// Firmware kept refusing ExitBootServices
+    ExitBootServices { attempts: usize, status: Status },
+    /// Firmware refused to switch runtime services to their virtual addresses
+    SetVirtualAddressMap(Status),
+    /// Firmware couldn't list the volumes with a SimpleFileSystem
+    LocateFilesystems(Status),
+    /// Firmware refused to reserve pages for something handed to the kernel
+    Alloc { what: &'static str, status: Status },
+    /// The GOP couldn't be set up for the kernel
+    Gop(gop::Error),
+    /// The page tables the kernel is entered on couldn't be built
+    #[cfg(target_arch = "x86_64")]
+    PageTables(paging::Error),
+    /// With `handoff = boot-services` the kernel runs on the firmware's identity map, which a
+    /// segment linked away from its physical address isn't reachable through
+    NotIdentityMapped { vaddr: u64, paddr: u64 },
+}
 
-    unsafe {
-        // create vector with correct cap & len, assign without drop or realloc to data
-        data.write(Vec::with_capacity(vec_size)).set_len(vec_size);
+impl core::fmt::Display for BootError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BootError::KernelNotFound(e) => write!(f, "{}", e),
+            BootError::KernelLoad(e) => write!(f, "unable to load kernel image: {}", e),
+            BootError::Digest { name, error } => write!(f, "{} {}", name, error),
+            BootError::InvalidDigest { name } => {
+                write!(f, "{} does not contain a valid SHA-256 digest", name)
+            }
+            BootError::Initrd { name, error } => write!(f, "initrd {} {}", name, error),
+            BootError::Dtb { name, error } => write!(f, "devicetree {} {}", name, error),
+            BootError::InvalidDtb { name, reason } => {
+                write!(f, "devicetree {} is invalid: {}", name, reason)
+            }
+            BootError::Config(e) => write!(f, "{} is invalid: {}", config::CONFIG_FILE_NAME, e),
+            BootError::Module { name, error } => write!(f, "boot module {} {}", name, error),
+            BootError::Console(status) => write!(f, "unable to set up the console: {:?}", status),
+            BootError::UnsupportedUefi { major, minor } => write!(
+                f,
+                "UEFI {}.{} is too old, 2.3 or later is required",
+                major,
+                minor / 10
+            ),
+            BootError::ExitBootServices { attempts, status } => write!(
+                f,
+                "failed to exit boot services after {} attempts: {:?}",
+                attempts, status
+            ),
+            BootError::SetVirtualAddressMap(status) => {
+                write!(f, "unable to set the virtual address map: {:?}", status)
+            }
+            BootError::LocateFilesystems(status) => {
+                write!(f, "unable to locate file system volumes: {:?}", status)
+            }
+            BootError::Alloc { what, status } => {
+                write!(f, "unable to allocate pages for {}: {:?}", what, status)
+            }
+            BootError::Gop(e) => write!(f, "{}", e),
+            #[cfg(target_arch = "x86_64")]
+            BootError::PageTables(e) => write!(f, "unable to build page tables: {}", e),
+            BootError::NotIdentityMapped { vaddr, paddr } => write!(
+                f,
+                "kernel segment {:#X} is loaded at {:#X}, handoff = boot-services only keeps the \
+                 firmware's identity map, use load = physical",
+                vaddr, paddr
+            ),
+        }
+    }
+}
+
+impl From<KernelNotFound> for BootError {
+    fn from(e: KernelNotFound) -> Self {
+        BootError::KernelNotFound(e)
+    }
+}
 
-        // init all elements to zero
-        let mut data = data.assume_init();
-        for e in &mut data {
-            *e = 0;
+impl From<KernelLoadError> for BootError {
+    fn from(e: KernelLoadError) -> Self {
+        BootError::KernelLoad(e)
+    }
+}
+
+/// Reasons the kernel image could not be loaded into memory
+#[derive(Debug)]
+enum KernelLoadError {
+    /// The kernel file could not be read from the volume
+    File {
+        name: arrayvec::ArrayString<64>,
+        error: FileError,
+    },
+    /// The image is a PE/COFF executable, which the loader can't load
+    PeImage,
+    /// The image doesn't start with the ELF magic, e.g. a flat binary
+    NotElf,
+    /// goblin was unable to parse the image as an ELF binary
+    ElfParse(goblin::error::Error),
+    /// The image is not an ELF64 binary for the machine we are running on
+    UnsupportedMachine { machine: u16, is_64: bool },
+    /// The image has no program headers, so there is nothing to load
+    NoProgramHeaders,
+    /// Firmware refused to reserve the pages a segment needs to be loaded at
+    SegmentAllocFailed {
+        addr: u64,
+        pages: usize,
+        status: Status,
+    },
+    /// The image has program headers, but none of them are PT_LOAD
+    NoLoadableSegments { e_type: u16 },
+    /// The image has more PT_LOAD segments than the loader can describe to the kernel
+    TooManySegments,
+    /// A range of PT_LOAD pages is bigger than the largest region of free memory
+    TooLarge { required: u64, available: u64 },
+    /// A segment read back with a different CRC-32 than the bytes copied into it
+    #[cfg(feature = "verify-copy")]
+    CopyMismatch {
+        vaddr: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// A word in the pages reserved for the kernel didn't read back what was written to it
+    MemTest(memtest::Failure),
+    /// A segment would be copied over the loader's own image
+    OverlapsLoader {
+        start: u64,
+        end: u64,
+        loader_start: u64,
+        loader_end: u64,
+    },
+    /// Every copy of the image buffer firmware handed out overlapped the pages it loads to
+    BufferInLoadRegion { attempts: usize },
+    /// A segment would be copied over memory the firmware doesn't report as free, `None` if the
+    /// range isn't described by the memory map at all
+    SegmentOverlap {
+        start: u64,
+        end: u64,
+        memory_type: Option<MemoryType>,
+    },
+    /// A PT_LOAD segment's file range extends past the end of the image
+    SegmentOutOfBounds {
+        offset: u64,
+        filesz: u64,
+        file_len: usize,
+    },
+    /// The entry point isn't covered by any PT_LOAD segment
+    EntryNotLoaded { entry: u64 },
+    /// A PIE kernel contains a relocation type the loader can't apply
+    UnsupportedRelocation { r_type: u32, offset: u64 },
+    /// A relocation targets an address outside every loadable segment
+    RelocationNotLoaded { offset: u64 },
+    /// The image looked like gzip but couldn't be decompressed
+    Gzip(gzip::Error),
+    /// The image doesn't match the digest in its companion .sha256 file
+    DigestMismatch {
+        computed: sha256::Digest,
+        expected: sha256::Digest,
+    },
+}
+
+impl core::fmt::Display for KernelLoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KernelLoadError::File { name, error } => write!(f, "{} {}", name, error),
+            KernelLoadError::Gzip(e) => write!(f, "error decompressing kernel: {}", e),
+            KernelLoadError::PeImage => write!(f, "PE kernels are not supported"),
+            KernelLoadError::NotElf => write!(f, "not an ELF image"),
+            KernelLoadError::ElfParse(e) => write!(f, "error parsing ELF: {}", e),
+            KernelLoadError::UnsupportedMachine { machine, is_64 } => write!(
+                f,
+                "unsupported kernel image: {} ELF{}, expected {} ELF64",
+                header::machine_to_str(*machine),
+                if *is_64 { 64 } else { 32 },
+                header::machine_to_str(KERNEL_MACHINE)
+            ),
+            KernelLoadError::NoProgramHeaders => write!(f, "ELF image has no program headers"),
+            KernelLoadError::NoLoadableSegments { e_type } => write!(
+                f,
+                "no loadable segments found in {} ELF image",
+                header::et_to_str(*e_type)
+            ),
+            KernelLoadError::TooLarge {
+                required,
+                available,
+            } => write!(
+                f,
+                "kernel needs {} contiguous bytes, the largest free region is {} bytes",
+                required, available
+            ),
+            KernelLoadError::SegmentAllocFailed {
+                addr,
+                pages,
+                status,
+            } => write!(
+                f,
+                "unable to reserve {} pages @ {:#X} for kernel segment: {:?}",
+                pages, addr, status
+            ),
+            #[cfg(feature = "verify-copy")]
+            KernelLoadError::CopyMismatch {
+                vaddr,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "segment @ {:#X} reads back with CRC-32 {:#010X}, expected {:#010X}",
+                vaddr, actual, expected
+            ),
+            KernelLoadError::MemTest(e) => write!(
+                f,
+                "memory test failed @ {:#X}, wrote {:#018X} but read back {:#018X}",
+                e.addr, e.expected, e.actual
+            ),
+            KernelLoadError::OverlapsLoader {
+                start,
+                end,
+                loader_start,
+                loader_end,
+            } => write!(
+                f,
+                "kernel segment pages {:#X} - {:#X} overlap the loader image at {:#X} - {:#X}",
+                start, end, loader_start, loader_end
+            ),
+            KernelLoadError::BufferInLoadRegion { attempts } => write!(
+                f,
+                "no copy of the kernel image buffer in {} attempts was outside its load region",
+                attempts
+            ),
+            KernelLoadError::SegmentOverlap {
+                start,
+                end,
+                memory_type: Some(ty),
+            } => write!(
+                f,
+                "kernel segment pages {:#X} - {:#X} overlap {:?} memory",
+                start, end, ty
+            ),
+            KernelLoadError::SegmentOverlap {
+                start,
+                end,
+                memory_type: None,
+            } => write!(
+                f,
+                "kernel segment pages {:#X} - {:#X} are not in the memory map, possibly MMIO",
+                start, end
+            ),
+            KernelLoadError::SegmentOutOfBounds {
+                offset,
+                filesz,
+                file_len,
+            } => write!(
+                f,
+                "segment at file offset {:#X} ({:#X} bytes) extends past the end of the {:#X} byte image",
+                offset, filesz, file_len
+            ),
+            KernelLoadError::EntryNotLoaded { entry } => write!(
+                f,
+                "entry point {:#X} is outside every loadable segment",
+                entry
+            ),
+            KernelLoadError::DigestMismatch { computed, expected } => write!(
+                f,
+                "kernel image SHA-256 mismatch, computed {} expected {}",
+                sha256::Hex(computed),
+                sha256::Hex(expected)
+            ),
+            KernelLoadError::UnsupportedRelocation { r_type, offset } => write!(
+                f,
+                "unsupported relocation {} @ {:#X}",
+                reloc::r_to_str(*r_type, KERNEL_MACHINE),
+                offset
+            ),
+            KernelLoadError::RelocationNotLoaded { offset } => write!(
+                f,
+                "relocation @ {:#X} is outside every loadable segment",
+                offset
+            ),
+            KernelLoadError::TooManySegments => write!(
+                f,
+                "kernel has more than {} loadable segments",
+                MAX_KERNEL_SEGMENTS
+            ),
         }
+    }
+}
+
+/// Watchdog code passed to SetWatchdogTimer, codes up to 0xFFFF are reserved for firmware
+const WATCHDOG_CODE: u64 = 0x1_0000;
+
+/// With `runtime_virtual` set, runtime services regions are remapped to their physical address
+/// plus this offset, the start of the higher half
+const RUNTIME_VIRT_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+/// Load bias applied to position independent (ET_DYN) kernels
+const PIE_LOAD_BASE: u64 = 0x100_0000;
 
-        // return Vec<u8>
-        data
+/// Relocation type that only needs the load bias added, the only kind a PIE kernel should contain
+#[cfg(target_arch = "x86_64")]
+const RELATIVE_RELOC: u32 = reloc::R_X86_64_RELATIVE;
+#[cfg(target_arch = "aarch64")]
+const RELATIVE_RELOC: u32 = reloc::R_AARCH64_RELATIVE;
+
+/// Placeholder relocation type that has no effect
+#[cfg(target_arch = "x86_64")]
+const NONE_RELOC: u32 = reloc::R_X86_64_NONE;
+#[cfg(target_arch = "aarch64")]
+const NONE_RELOC: u32 = reloc::R_AARCH64_NONE;
+
+/// Apply the dynamic relocations (DT_RELA) of a position independent kernel that has been loaded
+/// at `load_bias`. Relocation offsets are link time virtual addresses, the fixup is written to the
+/// physical copy of the segment that contains them.
+fn apply_relocations(obj: &goblin::elf::Elf, load_bias: u64) -> Result<(), KernelLoadError> {
+    let mut applied = 0;
+    for rela in obj.dynrelas.iter() {
+        match rela.r_type {
+            RELATIVE_RELOC => {
+                let paddr = obj
+                    .program_headers
+                    .iter()
+                    .find(|ph| {
+                        ph.p_type == program_header::PT_LOAD
+                            && rela.r_offset >= ph.p_vaddr
+                            && rela.r_offset + 8 <= ph.p_vaddr + ph.p_memsz
+                    })
+                    .map(|ph| rela.r_offset - ph.p_vaddr + ph.p_paddr + load_bias)
+                    .ok_or(KernelLoadError::RelocationNotLoaded {
+                        offset: rela.r_offset,
+                    })?;
+                let value = (load_bias as i64 + rela.r_addend.unwrap_or(0)) as u64;
+                unsafe { (paddr as *mut u64).write_unaligned(value) };
+                applied += 1;
+            }
+            NONE_RELOC => {}
+            other => {
+                return Err(KernelLoadError::UnsupportedRelocation {
+                    r_type: other,
+                    offset: rela.r_offset,
+                })
+            }
+        }
+    }
+    info!("Applied {} relocations", applied);
+    Ok(())
+}
+
+/// Maximum number of PT_LOAD segments reported to the kernel
+const MAX_KERNEL_SEGMENTS: usize = 16;
+
+/// Copies of the image buffer made looking for one outside the kernel's load region before
+/// giving up, each one is held until then so firmware can't hand out the same pages again
+const MAX_BUFFER_MOVES: usize = 4;
+
+/// A loaded PT_LOAD segment, as reported to the kernel through `EBootTable::segments`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct KernelSegment {
+    vaddr: u64,
+    /// Physical address the segment was copied to
+    paddr: u64,
+    /// Size of the segment in memory in bytes, including any .bss
+    size: u64,
+    /// ELF `p_flags` of the segment, PF_X = 0x1, PF_W = 0x2, PF_R = 0x4
+    flags: u32,
+}
+
+/// Result of loading the kernel image into memory
+struct LoadedKernel {
+    entry: *const (),
+    segments: ArrayVec<KernelSegment, MAX_KERNEL_SEGMENTS>,
+    /// Bytes copied out of the image, the segments' `p_filesz` summed
+    copied: u64,
+    /// Random part of the load bias, see `kaslr`
+    kaslr_offset: u64,
+    tls: Option<TlsTemplate>,
+}
+
+/// The kernel's PT_TLS segment, the template each thread's TLS block is initialized from. Its
+/// bytes are already in memory as part of a PT_LOAD segment, so it isn't copied anywhere itself.
+struct TlsTemplate {
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Print every header of `obj`, for `dump = elf`
+fn dump_elf(obj: &goblin::elf::Elf) {
+    info!("ELF header: {:#?}", obj.header);
+
+    for (index, ph) in obj.program_headers.iter().enumerate() {
+        info!(
+            "Program header {}: {} offset {:#X} vaddr {:#X} paddr {:#X} filesz {:#X} memsz {:#X} flags {:#X} align {:#X}",
+            index,
+            program_header::pt_to_str(ph.p_type),
+            ph.p_offset,
+            ph.p_vaddr,
+            ph.p_paddr,
+            ph.p_filesz,
+            ph.p_memsz,
+            ph.p_flags,
+            ph.p_align
+        );
+    }
+
+    for (index, sh) in obj.section_headers.iter().enumerate() {
+        info!(
+            "Section header {}: {} type {:#X} addr {:#X} offset {:#X} size {:#X} flags {:#X} align {:#X}",
+            index,
+            obj.shdr_strtab.get_at(sh.sh_name).unwrap_or("<invalid name>"),
+            sh.sh_type,
+            sh.sh_addr,
+            sh.sh_offset,
+            sh.sh_size,
+            sh.sh_flags,
+            sh.sh_addralign
+        );
+    }
+}
+
+/// Check there is enough free memory for every PT_LOAD segment before reserving any of it, so a
+/// kernel too big for the machine fails up front rather than on whichever segment runs out.
+/// Segments sharing pages are merged first, and every merged range has to fit in one free region.
+fn check_kernel_fits(
+    bs: &BootServices,
+    obj: &goblin::elf::Elf,
+    load_bias: u64,
+) -> Result<(), KernelLoadError> {
+    let ranges = segment::segment_pages::<MAX_KERNEL_SEGMENTS>(obj, load_bias)
+        .ok_or(KernelLoadError::TooManySegments)?;
+
+    let mut mmap_buf = create_mmap_buf(bs);
+    let descriptors = match bs.memory_map(&mut mmap_buf) {
+        Ok(map) => map.log().1,
+        Err(e) => {
+            warn!(
+                "Unable to get memory map to check the kernel fits: {:?}",
+                e.status()
+            );
+            return Ok(());
+        }
+    };
+
+    // firmware splits free memory into neighbouring descriptors freely, join them back up
+    let mut free: Vec<(u64, u64)> = descriptors
+        .filter(|d| d.ty == MemoryType::CONVENTIONAL)
+        .map(|d| (d.phys_start, d.phys_start + d.page_count * PAGE_SIZE))
+        .collect();
+    free.sort_unstable_by_key(|r| r.0);
+    let mut largest = 0;
+    let mut run = (0, 0);
+    for (start, end) in free {
+        if start != run.1 {
+            run.0 = start;
+        }
+        run.1 = end;
+        largest = largest.max(run.1 - run.0);
+    }
+
+    for (start, end) in ranges {
+        debug!(
+            "Kernel pages {:#X}-{:#X} need {:#X} bytes, largest free region is {:#X} bytes",
+            start,
+            end,
+            end - start,
+            largest
+        );
+        if end - start > largest {
+            return Err(KernelLoadError::TooLarge {
+                required: end - start,
+                available: largest,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Lowest and highest physical address of the PT_LOAD segments, before any load bias
+fn load_span(obj: &goblin::elf::Elf) -> (u64, u64) {
+    segment::loadable(obj).fold((u64::MAX, 0), |(start, end), ph| {
+        (start.min(ph.p_paddr), end.max(ph.p_paddr + ph.p_memsz))
+    })
+}
+
+/// Reserve the physical pages every PT_LOAD segment is copied to, so firmware won't hand them out
+/// to anyone else before we exit. Segments that aren't page aligned can share a page, and
+/// allocating the same page twice fails, so the union of their page ranges is reserved instead.
+fn reserve_segment_pages(
+    bs: &BootServices,
+    obj: &goblin::elf::Elf,
+    load_bias: u64,
+    overlap: OverlapCheck,
+    memtest: bool,
+    loader_image: Option<(u64, u64)>,
+) -> Result<(), KernelLoadError> {
+    let merged = segment::segment_pages::<MAX_KERNEL_SEGMENTS>(obj, load_bias)
+        .ok_or(KernelLoadError::TooManySegments)?;
+
+    // firmware should refuse to hand out the pages the loader runs from, but copying over them
+    // would corrupt the code doing the copy, so don't rely on it
+    if let Some((loader_start, loader_end)) = loader_image {
+        if let Some(&(start, end)) = merged
+            .iter()
+            .find(|(start, end)| *start < loader_end && *end > loader_start)
+        {
+            return Err(KernelLoadError::OverlapsLoader {
+                start,
+                end,
+                loader_start,
+                loader_end,
+            });
+        }
+    }
+
+    check_segment_overlap(bs, &merged, overlap)?;
+
+    for (start, end) in merged {
+        let pages = ((end - start) / PAGE_SIZE) as usize;
+        info!(
+            "Reserving {} pages @ {:#X} for program headers",
+            pages, start
+        );
+        bs.allocate_pages(
+            AllocateType::Address(start as usize),
+            memtype::KERNEL_IMAGE,
+            pages,
+        )
+        .map_err(|e| KernelLoadError::SegmentAllocFailed {
+            addr: start,
+            pages,
+            status: e.status(),
+        })?
+        .log();
+
+        // the pages are ours now and about to be overwritten by the copy anyway
+        if memtest {
+            memtest::test_range(start, end).map_err(KernelLoadError::MemTest)?;
+            debug!("Memory test passed for {:#X} - {:#X}", start, end);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `buf` shares a page with any of the page `spans` the kernel is copied to
+fn buffer_overlaps_spans(spans: &[(u64, u64)], buf: &[u8]) -> bool {
+    let (buf_start, buf_end) = page_span(buf.as_ptr() as u64, buf.len() as u64);
+    spans
+        .iter()
+        .any(|&(start, end)| start < buf_end && end > buf_start)
+}
+
+/// Physical address range the loader's own image occupies, from its LoadedImage protocol
+fn loader_image_range(bs: &BootServices, image: Handle) -> Option<(u64, u64)> {
+    let params = OpenProtocolParams {
+        handle: image,
+        agent: image,
+        controller: None,
+    };
+    let loaded_image: ScopedProtocol<LoadedImage> =
+        match bs.open_protocol(params, OpenProtocolAttributes::GetProtocol) {
+            Ok(sp) => sp.log(),
+            Err(e) => {
+                warn!("Unable to open LoadedImage protocol: {:?}", e.status());
+                return None;
+            }
+        };
+
+    let (base, size) = unsafe { &*loaded_image.interface.get() }.info();
+    Some((base as u64, base as u64 + size))
+}
+
+/// Compare the page ranges segments are about to be copied to against the memory map. Anything
+/// other than conventional memory, or a gap in the map that could be MMIO, is reported and with
+/// `OverlapCheck::Abort` the load is refused before a single byte has been written.
+fn check_segment_overlap(
+    bs: &BootServices,
+    ranges: &[(u64, u64)],
+    overlap: OverlapCheck,
+) -> Result<(), KernelLoadError> {
+    let mut mmap_buf = create_mmap_buf(bs);
+    let descriptors = match bs.memory_map(&mut mmap_buf) {
+        Ok(map) => map.log().1,
+        Err(e) => {
+            warn!(
+                "Unable to get memory map to check segments against: {:?}",
+                e.status()
+            );
+            return Ok(());
+        }
+    };
+
+    for &(start, end) in ranges {
+        let mut covered = 0;
+        let mut conflict = None;
+        for d in descriptors.clone() {
+            let d_end = d.phys_start + d.page_count * PAGE_SIZE;
+            if d.phys_start >= end || d_end <= start {
+                continue;
+            }
+            covered += d_end.min(end) - d.phys_start.max(start);
+            if d.ty != MemoryType::CONVENTIONAL {
+                conflict = Some(Some(d.ty));
+            }
+        }
+        if covered < end - start {
+            conflict = conflict.or(Some(None));
+        }
+
+        if let Some(memory_type) = conflict {
+            let err = KernelLoadError::SegmentOverlap {
+                start,
+                end,
+                memory_type,
+            };
+            match overlap {
+                OverlapCheck::Abort => return Err(err),
+                OverlapCheck::Warn => warn!("{}", err),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the kernel into memory, if `expected_digest` is given the image must hash to it
+fn load_kernel_image(
+    source: KernelSource,
+    kernel_name: &str,
+    bs: &BootServices,
+    expected_digest: Option<&sha256::Digest>,
+    config: &BootConfig,
+    progress: Option<&gop::ProgressBar>,
+    loader_image: Option<(u64, u64)>,
+) -> Result<LoadedKernel, KernelLoadError> {
+    let load_mode = config.load;
+
+    let mut kern_buf = match source {
+        KernelSource::File(handle) => {
+            let retry = ReadRetry {
+                bs,
+                retries: config.read_retries,
+            };
+            read_file(handle, Some(retry)).map_err(|error| KernelLoadError::File {
+                name: arrayvec::ArrayString::from(kernel_name).unwrap_or_default(),
+                error,
+            })?
+        }
+        KernelSource::Blocks(data) => data,
+    };
+    let kernel_size = kern_buf.len();
+    if let Some(bar) = progress {
+        bar.advance(gop::Stage::Read);
+    }
+    #[cfg(feature = "profile")]
+    profile::mark(profile::Mark::KernelRead);
+
+    if let Some(expected) = expected_digest {
+        let computed = sha256::digest(&kern_buf);
+        if computed != *expected {
+            return Err(KernelLoadError::DigestMismatch {
+                computed,
+                expected: *expected,
+            });
+        }
+        info!("Kernel image SHA-256 verified: {}", sha256::Hex(&computed));
+    }
+
+    // the digest covers the file as stored, so only decompress once it has been checked
+    if gzip::is_gzip(&kern_buf) {
+        kern_buf = gzip::decompress(&kern_buf).map_err(KernelLoadError::Gzip)?;
+        info!(
+            "Decompressed gzip kernel image, {} -> {} bytes",
+            kernel_size,
+            kern_buf.len()
+        );
+    }
+
+    // goblin's errors for something that isn't ELF at all don't say what it is instead
+    if !kern_buf.starts_with(header::ELFMAG) {
+        return Err(if kern_buf.starts_with(b"MZ") {
+            KernelLoadError::PeImage
+        } else {
+            KernelLoadError::NotElf
+        });
+    }
+    let obj = goblin::elf::Elf::parse(&kern_buf).map_err(KernelLoadError::ElfParse)?;
+
+    // dump before any validation, the point is to look at images that won't load
+    if config.dump == Some(Dump::Elf) {
+        dump_elf(&obj);
+        info!("ELF dump complete, halting");
+        panic::halt();
+    }
+
+    // refuse to touch memory for an image we can't possibly run
+    if obj.header.e_machine != KERNEL_MACHINE || !obj.is_64 {
+        return Err(KernelLoadError::UnsupportedMachine {
+            machine: obj.header.e_machine,
+            is_64: obj.is_64,
+        });
+    }
+
+    if obj.program_headers.is_empty() {
+        return Err(KernelLoadError::NoProgramHeaders);
     }
+
+    // e.g. a relocatable object file, nothing would be copied and the jump would go nowhere
+    let load_count = segment::loadable(&obj).count();
+    if load_count == 0 {
+        return Err(KernelLoadError::NoLoadableSegments {
+            e_type: obj.header.e_type,
+        });
+    }
+
+    // the copy loop trusts these, a truncated or crafted image would have it read past kern_buf
+    if let Some(ph) = segment::out_of_bounds(&obj, kern_buf.len()) {
+        return Err(KernelLoadError::SegmentOutOfBounds {
+            offset: ph.p_offset,
+            filesz: ph.p_filesz,
+            file_len: kern_buf.len(),
+        });
+    }
+
+    // jumping to an address no segment populates would crash without any useful output
+    let entry_ph = segment::loadable(&obj)
+        .find(|ph| obj.header.e_entry >= ph.p_vaddr && obj.header.e_entry < ph.p_vaddr + ph.p_memsz)
+        .ok_or(KernelLoadError::EntryNotLoaded {
+            entry: obj.header.e_entry,
+        })?;
+
+    if let Some(bar) = progress {
+        bar.advance(gop::Stage::Parsed);
+    }
+    #[cfg(feature = "profile")]
+    profile::mark(profile::Mark::ElfParsed);
+
+    // position independent kernels are linked at 0 and have to be slid to where we load them
+    let (load_bias, kaslr_offset) = if obj.header.e_type == header::ET_DYN {
+        let base = config.load_base.unwrap_or(PIE_LOAD_BASE);
+        let kaslr_offset = if config.kaslr {
+            let (start, end) = load_span(&obj);
+            kaslr::pick_offset(bs, start + base, end + base)
+        } else {
+            0
+        };
+        info!(
+            "Found position independent kernel, loading @ {:#X}",
+            base + kaslr_offset
+        );
+        (base + kaslr_offset, kaslr_offset)
+    } else {
+        if config.load_base.is_some() {
+            warn!("load_base has no effect on a kernel that isn't position independent");
+        }
+        if config.kaslr {
+            warn!("kaslr has no effect on a kernel that isn't position independent");
+        }
+        (0, 0)
+    };
+
+    let entry = match load_mode {
+        LoadMode::Virtual => obj.header.e_entry + load_bias,
+        LoadMode::Physical => obj.header.e_entry - entry_ph.p_vaddr + entry_ph.p_paddr + load_bias,
+    };
+
+    info!(
+        "Found ELF binary with an entry point @ 0x{:X}, loaded {} bytes",
+        entry, kernel_size
+    );
+    let entry_point: usize = entry
+        .try_into()
+        .expect("unable to convert to platform native entry point");
+
+    check_kernel_fits(bs, &obj, load_bias)?;
+
+    // the pages reserved next have to be free, and the image buffer is the one allocation of ours
+    // that could be sitting on them, e.g. for a kernel loaded low. Firmware is free to hand out
+    // the same pages again once a buffer is dropped, so every copy that still overlaps is held
+    // until one lands somewhere else.
+    let spans = segment::segment_pages::<MAX_KERNEL_SEGMENTS>(&obj, load_bias)
+        .ok_or(KernelLoadError::TooManySegments)?;
+    let obj = if buffer_overlaps_spans(&spans, &kern_buf) {
+        drop(obj);
+        let mut rejected = ArrayVec::<Vec<u8>, MAX_BUFFER_MOVES>::new();
+        loop {
+            if rejected.is_full() {
+                return Err(KernelLoadError::BufferInLoadRegion {
+                    attempts: MAX_BUFFER_MOVES,
+                });
+            }
+            let moved = kern_buf.clone();
+            rejected.push(core::mem::replace(&mut kern_buf, moved));
+            if !buffer_overlaps_spans(&spans, &kern_buf) {
+                break;
+            }
+        }
+        info!(
+            "Kernel image buffer overlaps its load region, moved it to {:#X}",
+            kern_buf.as_ptr() as usize
+        );
+        drop(rejected);
+        goblin::elf::Elf::parse(&kern_buf).map_err(KernelLoadError::ElfParse)?
+    } else {
+        obj
+    };
+
+    reserve_segment_pages(
+        bs,
+        &obj,
+        load_bias,
+        config.overlap_check,
+        config.memtest,
+        loader_image,
+    )?;
+
+    let mut segments = ArrayVec::<KernelSegment, MAX_KERNEL_SEGMENTS>::new();
+    let mut copied = 0;
+
+    // only PT_LOAD segments describe memory that needs to be populated
+    for ph in segment::loadable(&obj) {
+        info!("Found ELF program header >\nELF Offset:\t{:#X}\nLoad address:\t{:#X} & {:#X}\nFile image size:\t{:#X} bytes\nSize in memory:\t{:#X} bytes",
+            ph.p_offset, ph.p_vaddr, ph.p_paddr, ph.p_filesz, ph.p_memsz
+        );
+
+        let paddr = ph.p_paddr + load_bias;
+        // a kernel running from its physical addresses doesn't need anything remapped
+        let vaddr = match load_mode {
+            LoadMode::Virtual => ph.p_vaddr + load_bias,
+            LoadMode::Physical => paddr,
+        };
+
+        segments
+            .try_push(KernelSegment {
+                vaddr,
+                paddr,
+                size: ph.p_memsz,
+                flags: ph.p_flags,
+            })
+            .map_err(|_| KernelLoadError::TooManySegments)?;
+
+        let src = &kern_buf[ph.p_offset as usize..][..ph.p_filesz as usize];
+        // the segment may be linked somewhere other than where it lives physically, only the
+        // physical address is mapped until the kernel's page tables are loaded. The copy goes to
+        // paddr itself, not the page it was rounded down to when reserving, so the offset within
+        // the first page is kept.
+        // the destination pages were reserved, so firmware can't have had kern_buf there
+        let src_ptr = src.as_ptr() as u64;
+        assert!(
+            src_ptr + ph.p_filesz <= paddr || paddr + ph.p_memsz <= src_ptr,
+            "segment copy source and destination overlap"
+        );
+        info!(
+            "Copying program header from {:#X} to {:#X}, count: {:#X} bytes",
+            src_ptr, paddr, ph.p_filesz
+        );
+        if ph.p_memsz > ph.p_filesz {
+            info!(
+                "Zeroing .bss at {:#X}, count: {:#X} bytes",
+                paddr + ph.p_filesz,
+                ph.p_memsz - ph.p_filesz
+            );
+        }
+        let dest =
+            unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, ph.p_memsz as usize) };
+        segment::copy_segment(&kern_buf, ph, dest);
+        copied += ph.p_filesz;
+
+        // memory that ignores writes, like MMIO or bad RAM, reads back as something else
+        #[cfg(feature = "verify-copy")]
+        {
+            let expected = crc32::crc32(src);
+            let actual = crc32::crc32(&dest[..src.len()]);
+            if actual != expected {
+                return Err(KernelLoadError::CopyMismatch {
+                    vaddr,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+
+    if load_bias != 0 {
+        apply_relocations(&obj, load_bias)?;
+    }
+    if let Some(bar) = progress {
+        bar.advance(gop::Stage::SegmentsLoaded);
+    }
+    #[cfg(feature = "profile")]
+    profile::mark(profile::Mark::SegmentsLoaded);
+
+    // purely informational, and slow to scroll past on the console for large kernels
+    #[cfg(feature = "verbose-sections")]
+    for s in obj.section_headers {
+        let section_name = obj
+            .shdr_strtab
+            .get_at(s.sh_name)
+            .expect("error parsing section name");
+        if section_name.is_empty() {
+            continue;
+        }
+        info!(
+            "Found ELF section header {}\t> {:#X} - {:#X}\t({} bytes)\tALIGN: {:#X}\tFLAGS: {:#X}",
+            section_name,
+            s.sh_addr,
+            s.sh_addr + s.sh_size,
+            s.sh_size,
+            s.sh_addralign,
+            s.sh_flags
+        );
+    }
+
+    let tls = obj
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == program_header::PT_TLS)
+        .map(|ph| TlsTemplate {
+            vaddr: match load_mode {
+                LoadMode::Virtual => ph.p_vaddr + load_bias,
+                LoadMode::Physical => ph.p_paddr + load_bias,
+            },
+            filesz: ph.p_filesz,
+            memsz: ph.p_memsz,
+            align: ph.p_align,
+        });
+    if let Some(t) = &tls {
+        info!(
+            "Found TLS template @ {:#X}, {:#X} bytes initialized of {:#X}, align {:#X}",
+            t.vaddr, t.filesz, t.memsz, t.align
+        );
+    }
+
+    Ok(LoadedKernel {
+        entry: entry_point as *const (),
+        segments,
+        copied,
+        kaslr_offset,
+        tls,
+    })
+}
+
+/// Reserve `memtype::BOOT_INFO` pages big enough for the current memory map, for the final map
+/// handed to the kernel. Page alignment covers the 8 bytes descriptors need.
+fn stage_mmap_buf(bs: &BootServices) -> Result<&'static mut [u8], BootError> {
+    loop {
+        let mmap_size = bs.memory_map_size();
+        // reserving the pages can split a free region, so leave room for a few more descriptors
+        let size = mmap_size.map_size + mmap_size.entry_size * MMAP_EXTRA_DESCRIPTORS;
+        let pages = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let base = bs
+            .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+            .map_err(|e| BootError::Alloc {
+                what: "memory map",
+                status: e.status(),
+            })?
+            .log();
+
+        // the spare slots are a guess, check the map still fits now that the pages are taken
+        if bs.memory_map_size().map_size <= size {
+            return Ok(unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size) });
+        }
+        let _ = bs.free_pages(base, pages);
+    }
+}
+
+/// Build a buffer big enough to handle the current memory map
+///
+/// Descriptors must be 8 byte aligned. The global allocator serves every allocation of alignment
+/// 8 or less straight from AllocatePool, which UEFI guarantees to return 8 byte aligned memory,
+/// so a plain `Vec<u8>` is enough without an over-aligned layout.
+fn create_mmap_buf(bs: &BootServices) -> Vec<u8> {
+    let mmap_size = bs.memory_map_size();
+    // allocating the buffer itself can split a free region, so leave room for a few more descriptors
+    let vec_size = mmap_size.map_size + mmap_size.entry_size * MMAP_EXTRA_DESCRIPTORS;
+    let mut buf = create_vec_buf(vec_size);
+
+    // the spare slots are a guess, check the map still fits now that the buffer exists
+    loop {
+        let mmap_size = bs.memory_map_size();
+        if mmap_size.map_size <= buf.len() {
+            debug_assert_eq!(
+                buf.as_ptr() as usize % core::mem::align_of::<MemoryDescriptor>(),
+                0,
+                "memory map buffer is misaligned"
+            );
+            return buf;
+        }
+
+        let vec_size = mmap_size.map_size + mmap_size.entry_size * MMAP_EXTRA_DESCRIPTORS;
+        info!(
+            "Memory map grew to {} bytes while allocating its {} byte buffer, regrowing",
+            mmap_size.map_size,
+            buf.len()
+        );
+        // free the old buffer first so the new one can reuse its pages
+        drop(buf);
+        buf = create_vec_buf(vec_size);
+    }
+}
+
+fn create_vec_buf(vec_size: usize) -> Vec<u8> {
+    // vec! of a zero value goes through alloc_zeroed, which zeroes the whole buffer in one pass
+    // instead of writing each element
+    vec![0u8; vec_size]
 }
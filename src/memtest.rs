@@ -0,0 +1,53 @@
+//! Quick write/read-back test of the memory the kernel is about to be copied into.
+//!
+//! Only one word every `SAMPLE_STRIDE` bytes is tested, enough to catch a dead or aliased region
+//! without noticeably slowing down the boot.
+
+/// Distance between tested words
+const SAMPLE_STRIDE: u64 = 512;
+
+/// Fixed patterns tested before the address-as-data pass
+const PATTERNS: [u64; 4] = [
+    0x0000_0000_0000_0000,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xAAAA_AAAA_AAAA_AAAA,
+    0x5555_5555_5555_5555,
+];
+
+/// A sampled word that read back differently than it was written
+#[derive(Debug)]
+pub struct Failure {
+    pub addr: u64,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+/// Test the sampled words in `[start, end)`, which must be reserved and writable. Each pattern is
+/// written to every sample before any are read back, so writes landing on another sample show up.
+pub fn test_range(start: u64, end: u64) -> Result<(), Failure> {
+    let samples = || (start..end).step_by(SAMPLE_STRIDE as usize);
+
+    let patterns = PATTERNS
+        .iter()
+        .map(|p| (*p, false))
+        .chain(core::iter::once((0, true)));
+    for (pattern, address_as_data) in patterns {
+        let value = |addr: u64| if address_as_data { addr } else { pattern };
+
+        for addr in samples() {
+            unsafe { core::ptr::write_volatile(addr as *mut u64, value(addr)) };
+        }
+        for addr in samples() {
+            let actual = unsafe { core::ptr::read_volatile(addr as *const u64) };
+            if actual != value(addr) {
+                return Err(Failure {
+                    addr,
+                    expected: value(addr),
+                    actual,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
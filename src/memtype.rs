@@ -0,0 +1,26 @@
+//! Memory types used for the loader's page allocations.
+//!
+//! UEFI leaves `0x8000_0000..=0xFFFF_FFFF` for OS loaders to use. Tagging each allocation with
+//! its own type keeps them apart from the firmware's LOADER_DATA in the memory map handed to the
+//! kernel, so it can tell its own image from scratch memory it is free to reclaim.
+//!
+//! | type           | value         | contents                                        |
+//! |----------------|---------------|-------------------------------------------------|
+//! | `KERNEL_IMAGE` | `0x8000_0000` | PT_LOAD segments of the kernel                  |
+//! | `INITRD`       | `0x8000_0001` | initial ramdisk                                 |
+//! | `KERNEL_STACK` | `0x8000_0002` | stack the kernel is entered on                  |
+//! | `BOOT_INFO`    | `0x8000_0003` | `EBootTable`, cmdline and other handoff data    |
+//! | `PAGE_TABLES`  | `0x8000_0004` | page tables active when the kernel is entered   |
+//! | `BOOT_MODULE`  | `0x8000_0005` | contents of the files listed in `modules`       |
+//! | `DEVICE_TREE`  | `0x8000_0006` | flattened devicetree blob                       |
+
+use uefi::table::boot::MemoryType;
+
+pub const KERNEL_IMAGE: MemoryType = MemoryType::custom(0x8000_0000);
+pub const INITRD: MemoryType = MemoryType::custom(0x8000_0001);
+pub const KERNEL_STACK: MemoryType = MemoryType::custom(0x8000_0002);
+pub const BOOT_INFO: MemoryType = MemoryType::custom(0x8000_0003);
+#[cfg(target_arch = "x86_64")]
+pub const PAGE_TABLES: MemoryType = MemoryType::custom(0x8000_0004);
+pub const BOOT_MODULE: MemoryType = MemoryType::custom(0x8000_0005);
+pub const DEVICE_TREE: MemoryType = MemoryType::custom(0x8000_0006);
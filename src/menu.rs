@@ -0,0 +1,204 @@
+//! Boot countdown and interactive kernel selection on the UEFI console.
+//!
+//! Besides picking a kernel the menu can reboot the machine, straight into the firmware setup
+//! screen if the firmware supports being asked to through `OsIndications`.
+//!
+//! The countdown and the menu are written to the console directly rather than logged, so they
+//! still show with `log_level` set above `info`.
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use arrayvec::ArrayString;
+use uefi::prelude::*;
+use uefi::proto::console::text::Key;
+use uefi::proto::media::file::{Directory, FileAttribute};
+use uefi::table::runtime::{ResetType, VariableAttributes, VariableVendor};
+use uefi::CStr16;
+
+use crate::config::DEFAULT_KERNEL_NAME;
+
+/// How often the keyboard is polled while waiting, in microseconds
+const POLL_INTERVAL_US: usize = 10_000;
+
+/// Only single digit choices are offered
+const MAX_MENU_ENTRIES: usize = 9;
+
+/// EFI_OS_INDICATIONS_BOOT_TO_FW_UI, set in `OsIndications` to enter setup on the next boot
+const OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 0x1;
+
+/// Write a line to the console, whatever the log level. A console that can't be written to
+/// leaves nothing to report the failure on, so it is ignored.
+macro_rules! say {
+    ($st:expr, $($arg:tt)*) => {
+        let _ = writeln!($st.stdout(), $($arg)*);
+    };
+}
+
+/// Count down for `timeout` seconds, returning true if a key was pressed before it ran out
+pub fn wait_for_key(st: &mut SystemTable<Boot>, timeout: usize) -> bool {
+    // throw away anything typed before we started listening
+    let _ = st.stdin().reset(false);
+
+    let polls_per_second = 1_000_000 / POLL_INTERVAL_US;
+    for remaining in (1..=timeout).rev() {
+        say!(
+            st,
+            "Booting in {}s, press any key for the boot menu",
+            remaining
+        );
+        for _ in 0..polls_per_second {
+            if read_key(st).is_some() {
+                return true;
+            }
+            st.boot_services().stall(POLL_INTERVAL_US);
+        }
+    }
+    false
+}
+
+/// List the kernel images in `dir` and let the user pick one. Returns `None` to boot `default`.
+pub fn select_kernel(
+    st: &mut SystemTable<Boot>,
+    dir: &mut Directory,
+    default: &str,
+) -> Option<ArrayString<64>> {
+    let kernels = list_kernels(dir);
+    if kernels.is_empty() {
+        say!(st, "No kernel images found for the boot menu");
+        return None;
+    }
+
+    say!(st, "Boot menu:");
+    for (index, name) in kernels.iter().enumerate() {
+        let marker = if name.as_str() == default { '*' } else { ' ' };
+        say!(st, "  [{}]{} {}", index + 1, marker, name);
+    }
+    let fw_setup = firmware_setup_supported(st);
+    if fw_setup {
+        say!(st, "  [F]  Reboot into firmware setup");
+    } else {
+        say!(st, "  [F]  Reboot");
+    }
+    say!(st, "Select a kernel, or press Enter to boot {}", default);
+
+    loop {
+        match read_key(st) {
+            Some('\r') | Some('\n') => return None,
+            Some('f') | Some('F') => reboot(st, fw_setup),
+            Some(c) => match c.to_digit(10) {
+                Some(n) if n >= 1 && (n as usize) <= kernels.len() => {
+                    let choice = kernels[n as usize - 1];
+                    say!(st, "Booting {}", choice);
+                    return Some(choice);
+                }
+                _ => {
+                    say!(st, "Invalid selection '{}'", c);
+                }
+            },
+            None => st.boot_services().stall(POLL_INTERVAL_US),
+        }
+    }
+}
+
+/// Check `OsIndicationsSupported` for the boot to firmware UI bit
+fn firmware_setup_supported(st: &SystemTable<Boot>) -> bool {
+    let mut name_buf = [0u16; 24];
+    let name = CStr16::from_str_with_buf("OsIndicationsSupported", &mut name_buf).unwrap();
+    let mut buf = [0u8; 8];
+    match st
+        .runtime_services()
+        .get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut buf)
+    {
+        Ok(v) => {
+            let (data, _) = v.log();
+            data.len() == 8 && u64::from_le_bytes(buf) & OS_INDICATIONS_BOOT_TO_FW_UI != 0
+        }
+        Err(_) => false,
+    }
+}
+
+/// Reset the system, asking the firmware to stop in its setup screen if `fw_setup` is set.
+/// If the request can't be stored this is just a plain warm reset.
+fn reboot(st: &SystemTable<Boot>, fw_setup: bool) -> ! {
+    let rt = st.runtime_services();
+    if fw_setup {
+        let mut name_buf = [0u16; 16];
+        let name = CStr16::from_str_with_buf("OsIndications", &mut name_buf).unwrap();
+
+        // keep any other indications already pending
+        let mut buf = [0u8; 8];
+        let current = match rt.get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut buf) {
+            Ok(v) if v.log().0.len() == 8 => u64::from_le_bytes(buf),
+            _ => 0,
+        };
+        let value = current | OS_INDICATIONS_BOOT_TO_FW_UI;
+
+        let attributes = VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS;
+        match rt.set_variable(
+            name,
+            &VariableVendor::GLOBAL_VARIABLE,
+            attributes,
+            &value.to_le_bytes(),
+        ) {
+            Ok(_) => info!("Rebooting into firmware setup"),
+            Err(e) => warn!("Unable to set OsIndications: {:?}, rebooting", e.status()),
+        }
+    } else {
+        info!("Rebooting");
+    }
+
+    rt.reset(ResetType::Warm, Status::SUCCESS, None)
+}
+
+/// Poll the console for a printable key press
+fn read_key(st: &mut SystemTable<Boot>) -> Option<char> {
+    match st.stdin().read_key() {
+        Ok(key) => match key.log() {
+            Some(Key::Printable(c)) => Some(c.into()),
+            // still counts as a key press, but there is no character to act on
+            Some(Key::Special(_)) => Some('\0'),
+            None => None,
+        },
+        Err(_) => None,
+    }
+}
+
+/// Find the regular files in `dir` whose names start with the default kernel name
+fn list_kernels(dir: &mut Directory) -> Vec<ArrayString<64>> {
+    let mut kernels = Vec::new();
+
+    if dir.reset_entry_readout().is_err() {
+        return kernels;
+    }
+
+    // Must be alligned, so this is left as a heap allocation
+    let mut dir_buf = crate::create_vec_buf(128);
+
+    while let Some((name, attribute)) = crate::next_entry(dir, &mut dir_buf) {
+        if attribute.contains(FileAttribute::DIRECTORY) {
+            continue;
+        }
+
+        // digests sit next to the kernels but aren't bootable, FAT names are case insensitive
+        let prefix = DEFAULT_KERNEL_NAME.len();
+        let is_kernel = name
+            .get(..prefix)
+            .map_or(false, |p| p.eq_ignore_ascii_case(DEFAULT_KERNEL_NAME));
+        let is_digest = name
+            .len()
+            .checked_sub(".sha256".len())
+            .and_then(|start| name.get(start..))
+            .map_or(false, |s| s.eq_ignore_ascii_case(".sha256"));
+        if is_kernel && !is_digest {
+            kernels.push(name);
+            if kernels.len() == MAX_MENU_ENTRIES {
+                break;
+            }
+        }
+    }
+
+    kernels
+}
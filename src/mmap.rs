@@ -0,0 +1,86 @@
+//! Cleaned up copy of the UEFI memory map for kernels that just want a list of regions.
+
+use uefi::table::boot::{AllocateType, BootServices, MemoryDescriptor};
+use uefi::Status;
+
+use crate::{memtype, MMAP_EXTRA_DESCRIPTORS, PAGE_SIZE};
+
+/// Buffer for the sorted map, reserved while boot services are still available and filled in
+/// from the final map once they are gone.
+///
+/// Descriptors are stored back to back with a stride of `size_of::<MemoryDescriptor>()`, unlike
+/// the raw map which uses whatever descriptor size firmware reports.
+pub struct SortedMap {
+    buf: *mut MemoryDescriptor,
+    capacity: usize,
+    len: usize,
+}
+
+impl SortedMap {
+    /// Reserve room for every descriptor in the current map, plus slack for the allocations
+    /// still to come before exit_boot_services
+    pub fn reserve(bs: &BootServices) -> Result<SortedMap, Status> {
+        let map_size = bs.memory_map_size();
+        let capacity = map_size.map_size / map_size.entry_size + MMAP_EXTRA_DESCRIPTORS * 2;
+
+        let bytes = capacity * core::mem::size_of::<MemoryDescriptor>();
+        let pages = (bytes + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let buf = bs
+            .allocate_pages(AllocateType::AnyPages, memtype::BOOT_INFO, pages)
+            .map_err(|e| e.status())?
+            .log() as *mut MemoryDescriptor;
+
+        Ok(SortedMap {
+            buf,
+            capacity,
+            len: 0,
+        })
+    }
+
+    /// Copy `descriptors` in sorted by physical address, merging neighbours of the same type and
+    /// attributes. Must not allocate, this runs after exit_boot_services.
+    pub fn fill<'a>(&mut self, descriptors: impl Iterator<Item = &'a MemoryDescriptor>) {
+        let out = unsafe { core::slice::from_raw_parts_mut(self.buf, self.capacity) };
+
+        let mut len = 0;
+        for d in descriptors {
+            if len == out.len() {
+                warn!("Sorted memory map is full, dropping descriptors");
+                break;
+            }
+            out[len] = *d;
+            len += 1;
+        }
+
+        let out = &mut out[..len];
+        out.sort_unstable_by_key(|d| d.phys_start);
+
+        let mut merged = 0;
+        for i in 0..out.len() {
+            let d = out[i];
+            if merged > 0 {
+                let prev = &mut out[merged - 1];
+                if prev.ty == d.ty
+                    && prev.att == d.att
+                    && prev.phys_start + prev.page_count * PAGE_SIZE == d.phys_start
+                {
+                    prev.page_count += d.page_count;
+                    continue;
+                }
+            }
+            out[merged] = d;
+            merged += 1;
+        }
+
+        self.len = merged;
+    }
+
+    pub fn as_ptr(&self) -> *const MemoryDescriptor {
+        self.buf
+    }
+
+    /// Number of descriptors in the map
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
@@ -0,0 +1,32 @@
+//! Processor counts from the PI MP Services protocol.
+
+use uefi::prelude::*;
+use uefi::proto::pi::mp::{MpServices, ProcessorCount};
+
+/// Ask MP Services how many logical processors there are, None if the firmware doesn't expose
+/// the protocol or the call fails
+pub fn processor_count(bt: &BootServices) -> Option<ProcessorCount> {
+    let mp = match bt.locate_protocol::<MpServices>() {
+        Ok(mp) => mp.log(),
+        Err(e) => {
+            warn!("MP Services protocol not available: {:?}", e.status());
+            return None;
+        }
+    };
+    let mp = unsafe { &*mp.get() };
+
+    match mp.get_number_of_processors() {
+        Ok(count) => {
+            let count = count.log();
+            info!(
+                "Found {} logical processors, {} enabled",
+                count.total, count.enabled
+            );
+            Some(count)
+        }
+        Err(e) => {
+            warn!("Unable to get the processor count: {:?}", e.status());
+            None
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! x86_64 4-level page tables for the kernel, built while boot services are still available.
+//!
+//! Firmware only guarantees an identity map, which is no good for kernels linked in the higher
+//! half. The tables built here identity map physical memory with 2 MiB pages, so the loader keeps
+//! running after CR3 is switched, and map each kernel segment at its virtual address. A segment
+//! linked inside the identity mapped range would silently replace part of it, so that is refused.
+//!
+//! The tables are not loaded here. `enter_kernel` in main.rs acts as the trampoline, loading CR3
+//! and calling the kernel entry from one asm block that is identity mapped in both sets of tables.
+
+use uefi::table::boot::{AllocateType, BootServices, MemoryAttribute};
+use uefi::Status;
+
+use crate::{memtype, PAGE_SIZE};
+
+const ENTRIES_PER_TABLE: usize = 512;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+/// Set in a PD entry that maps a 2 MiB page directly
+const HUGE_PAGE: u64 = 1 << 7;
+/// Bits 12..52 of an entry hold the physical address of the next table or the page
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+
+/// Always identity map at least the low 4 GiB so MMIO like the local APIC stays reachable
+const MIN_IDENTITY_MAP: u64 = 0x1_0000_0000;
+
+/// Reasons the page tables couldn't be built
+#[derive(Debug)]
+pub enum Error {
+    /// Firmware couldn't provide a copy of the memory map
+    MemoryMap(Status),
+    /// No page could be reserved for another table
+    Alloc(Status),
+    /// `vaddr` is already mapped to `mapped`, e.g. a kernel segment linked inside the identity
+    /// mapped range, and can't also map `paddr`
+    Conflict { vaddr: u64, mapped: u64, paddr: u64 },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::MemoryMap(status) => write!(f, "unable to get the memory map: {:?}", status),
+            Error::Alloc(status) => write!(f, "unable to allocate a page table: {:?}", status),
+            Error::Conflict {
+                vaddr,
+                mapped,
+                paddr,
+            } => write!(
+                f,
+                "{:#X} is already mapped to {:#X}, can't map it to {:#X}",
+                vaddr, mapped, paddr
+            ),
+        }
+    }
+}
+
+#[repr(C, align(4096))]
+struct PageTable([u64; ENTRIES_PER_TABLE]);
+
+pub struct PageTables {
+    pml4: *mut PageTable,
+}
+
+impl PageTables {
+    pub fn new(bs: &BootServices) -> Result<PageTables, Error> {
+        Ok(PageTables {
+            pml4: alloc_table(bs)?,
+        })
+    }
+
+    /// Physical address of the PML4, the value loaded into CR3
+    pub fn pml4_addr(&self) -> u64 {
+        self.pml4 as u64
+    }
+
+    /// Identity map `[0, end)` using 2 MiB pages, `end` is raised to at least 4 GiB
+    pub fn identity_map(&mut self, bs: &BootServices, end: u64) -> Result<(), Error> {
+        let end = end.max(MIN_IDENTITY_MAP);
+        let mut addr = 0;
+        while addr < end {
+            let pd = self.walk_to_pd(bs, addr)?;
+            pd.0[table_index(addr, 1)] = addr | PRESENT | WRITABLE | HUGE_PAGE;
+            addr += HUGE_PAGE_SIZE;
+        }
+        info!("Identity mapped {:#X} bytes of physical memory", end);
+        Ok(())
+    }
+
+    /// Map `size` bytes at `vaddr` to `paddr` using 4 KiB pages. A page that is already mapped
+    /// somewhere else, by the identity map or an earlier call, is an error rather than replaced.
+    pub fn map(
+        &mut self,
+        bs: &BootServices,
+        vaddr: u64,
+        paddr: u64,
+        size: u64,
+        writable: bool,
+    ) -> Result<(), Error> {
+        let flags = if writable {
+            PRESENT | WRITABLE
+        } else {
+            PRESENT
+        };
+
+        let start = vaddr & !(PAGE_SIZE - 1);
+        let offset = vaddr - start;
+        let pages = (size + offset + PAGE_SIZE - 1) / PAGE_SIZE;
+        for page in 0..pages {
+            let virt = start + page * PAGE_SIZE;
+            let phys = (paddr - offset) + page * PAGE_SIZE;
+
+            let pd = self.walk_to_pd(bs, virt)?;
+            let pt = next_table(bs, pd, table_index(virt, 1))?;
+            let entry = &mut pt.0[table_index(virt, 0)];
+            let mapped = *entry & ADDR_MASK;
+            if *entry & PRESENT != 0 && mapped != phys {
+                return Err(Error::Conflict {
+                    vaddr: virt,
+                    mapped,
+                    paddr: phys,
+                });
+            }
+            // segments can share a page, don't drop write access another segment needs
+            let shared = *entry & WRITABLE;
+            *entry = phys | flags | shared;
+        }
+        Ok(())
+    }
+
+    fn walk_to_pd(&mut self, bs: &BootServices, vaddr: u64) -> Result<&mut PageTable, Error> {
+        let pml4 = unsafe { &mut *self.pml4 };
+        let pdpt = next_table(bs, pml4, table_index(vaddr, 3))?;
+        next_table(bs, pdpt, table_index(vaddr, 2))
+    }
+}
+
+/// Map every EFI_MEMORY_RUNTIME region in the firmware memory map at its physical address
+/// plus `offset`, matching the virtual map later handed to SetVirtualAddressMap
+pub fn map_runtime_services(
+    page_tables: &mut PageTables,
+    bs: &BootServices,
+    offset: u64,
+) -> Result<(), Error> {
+    let mut mmap_buf = crate::create_mmap_buf(bs);
+    let (_key, descriptors) = bs
+        .memory_map(&mut mmap_buf)
+        .map_err(|e| Error::MemoryMap(e.status()))?
+        .log();
+
+    for d in descriptors.filter(|d| d.att.contains(MemoryAttribute::RUNTIME)) {
+        let size = d.page_count * PAGE_SIZE;
+        debug!(
+            "Mapping runtime region {:#X} -> {:#X} ({:#X} bytes)",
+            d.phys_start + offset,
+            d.phys_start,
+            size
+        );
+        page_tables.map(bs, d.phys_start + offset, d.phys_start, size, true)?;
+    }
+    Ok(())
+}
+
+/// Highest physical address described by the firmware memory map
+pub fn physical_memory_end(bs: &BootServices) -> Result<u64, Error> {
+    let mut mmap_buf = crate::create_mmap_buf(bs);
+    let (_key, descriptors) = bs
+        .memory_map(&mut mmap_buf)
+        .map_err(|e| Error::MemoryMap(e.status()))?
+        .log();
+
+    Ok(descriptors
+        .map(|d| d.phys_start + d.page_count * PAGE_SIZE)
+        .max()
+        .unwrap_or(0))
+}
+
+/// Index into the table at `level` (0 = PT, 3 = PML4) that translates `vaddr`
+fn table_index(vaddr: u64, level: u32) -> usize {
+    ((vaddr >> (12 + 9 * level)) & (ENTRIES_PER_TABLE as u64 - 1)) as usize
+}
+
+/// Get the table referenced by `table[index]`, allocating it if it isn't present and splitting
+/// a 2 MiB page into 4 KiB pages if one is mapped there
+fn next_table<'a>(
+    bs: &BootServices,
+    table: &'a mut PageTable,
+    index: usize,
+) -> Result<&'a mut PageTable, Error> {
+    let entry = table.0[index];
+
+    if entry & PRESENT == 0 {
+        let next = alloc_table(bs)?;
+        table.0[index] = next as u64 | PRESENT | WRITABLE;
+        return Ok(unsafe { &mut *next });
+    }
+
+    if entry & HUGE_PAGE != 0 {
+        let next = alloc_table(bs)?;
+        let base = entry & ADDR_MASK;
+        let flags = entry & (PRESENT | WRITABLE);
+        let pt = unsafe { &mut *next };
+        for (i, e) in pt.0.iter_mut().enumerate() {
+            *e = (base + i as u64 * PAGE_SIZE) | flags;
+        }
+        table.0[index] = next as u64 | PRESENT | WRITABLE;
+        return Ok(pt);
+    }
+
+    Ok(unsafe { &mut *((entry & ADDR_MASK) as *mut PageTable) })
+}
+
+fn alloc_table(bs: &BootServices) -> Result<*mut PageTable, Error> {
+    let addr = bs
+        .allocate_pages(AllocateType::AnyPages, memtype::PAGE_TABLES, 1)
+        .map_err(|e| Error::Alloc(e.status()))?
+        .log();
+    let table = addr as *mut PageTable;
+    unsafe { bs.set_mem(table as *mut u8, PAGE_SIZE as usize, 0) };
+    Ok(table)
+}
@@ -0,0 +1,24 @@
+//! Panic handling that stays useful after boot services are gone.
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    // the logger reaches the serial port, and the UEFI console for as long as it is still around
+    error!("{}", info);
+
+    halt()
+}
+
+/// Stop the CPU for good, with interrupts disabled so nothing can wake it back up
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            #[cfg(target_arch = "x86_64")]
+            asm!("cli", "hlt", options(nomem, nostack));
+            #[cfg(target_arch = "aarch64")]
+            asm!("msr daifset, #0xf", "wfi", options(nomem, nostack));
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Boot timing, enabled with the `profile` feature.
+//!
+//! Timestamps come from the CPU's free running counter, the TSC on x86_64 (calibrated against
+//! `Stall` at startup) and the generic timer on aarch64. They are only logged, the kernel has its
+//! own clocks to measure the rest of boot with.
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::asm;
+
+use uefi::table::boot::BootServices;
+
+/// Points in efi_main a timestamp is taken at, in order
+#[derive(Clone, Copy)]
+pub enum Mark {
+    Start,
+    VolumeFound,
+    LoadStarted,
+    KernelRead,
+    ElfParsed,
+    SegmentsLoaded,
+    ExitStarted,
+    BootServicesExited,
+}
+
+const MARK_COUNT: usize = 8;
+
+/// Stages reported by `report`, as the pair of marks they run between
+const STAGES: [(&str, Mark, Mark); 6] = [
+    ("locate kernel volume", Mark::Start, Mark::VolumeFound),
+    ("read kernel", Mark::LoadStarted, Mark::KernelRead),
+    ("parse ELF", Mark::KernelRead, Mark::ElfParsed),
+    ("load segments", Mark::ElfParsed, Mark::SegmentsLoaded),
+    (
+        "exit boot services",
+        Mark::ExitStarted,
+        Mark::BootServicesExited,
+    ),
+    ("total", Mark::Start, Mark::BootServicesExited),
+];
+
+static mut MARKS: [u64; MARK_COUNT] = [0; MARK_COUNT];
+static mut TICKS_PER_US: u64 = 1;
+
+/// Work out the counter frequency and take the `Start` timestamp
+pub fn init(bs: &BootServices) {
+    unsafe {
+        TICKS_PER_US = ticks_per_us(bs).max(1);
+        MARKS[Mark::Start as usize] = ticks();
+    }
+}
+
+pub fn mark(mark: Mark) {
+    unsafe { MARKS[mark as usize] = ticks() };
+}
+
+/// Log the duration of every stage both of whose marks have been reached
+pub fn report() {
+    let (marks, ticks_per_us) = unsafe { (MARKS, TICKS_PER_US) };
+    for (name, from, to) in STAGES {
+        let (from, to) = (marks[from as usize], marks[to as usize]);
+        if from == 0 || to == 0 {
+            continue;
+        }
+        info!(
+            "Boot timing: {} took {} us",
+            name,
+            (to - from) / ticks_per_us
+        );
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn ticks() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn ticks_per_us(bs: &BootServices) -> u64 {
+    const CALIBRATION_US: usize = 10_000;
+    let start = ticks();
+    bs.stall(CALIBRATION_US);
+    (ticks() - start) / CALIBRATION_US as u64
+}
+
+#[cfg(target_arch = "aarch64")]
+fn ticks() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, cntvct_el0", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+#[cfg(target_arch = "aarch64")]
+fn ticks_per_us(_bs: &BootServices) -> u64 {
+    let freq: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nomem, nostack)) };
+    freq / 1_000_000
+}
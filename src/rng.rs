@@ -0,0 +1,70 @@
+//! Random bytes from the firmware's `EFI_RNG_PROTOCOL`, which the uefi crate doesn't wrap.
+//!
+//! Used to seed KASLR and to hand the kernel a seed for its own CSPRNG, through
+//! `EBootTable::rng_seed_ptr`.
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::table::boot::AllocateType;
+use uefi::{unsafe_guid, Guid};
+
+/// Bytes of seed handed to the kernel
+const SEED_LEN: usize = 32;
+
+#[repr(C)]
+#[unsafe_guid("3152bca5-eade-433d-862e-c01cdc291f44")]
+#[derive(Protocol)]
+struct Rng {
+    _get_info: extern "efiapi" fn(this: &Rng, list_size: &mut usize, list: *mut Guid) -> Status,
+    get_rng: extern "efiapi" fn(
+        this: &Rng,
+        algorithm: *const Guid,
+        value_len: usize,
+        value: *mut u8,
+    ) -> Status,
+}
+
+/// Fill `buf` with random bytes, false if there is no RNG protocol or it failed
+pub fn fill(bt: &BootServices, buf: &mut [u8]) -> bool {
+    let rng = match bt.locate_protocol::<Rng>() {
+        Ok(rng) => rng.log(),
+        Err(_) => return false,
+    };
+    let rng = unsafe { &*rng.get() };
+
+    // a null algorithm lets the firmware use its default
+    let status = (rng.get_rng)(rng, core::ptr::null(), buf.len(), buf.as_mut_ptr());
+    if status.is_error() {
+        warn!("RNG protocol failed: {:?}", status);
+        return false;
+    }
+    true
+}
+
+/// Copy `SEED_LEN` random bytes into a `memtype::BOOT_INFO` page for the kernel, None if the
+/// firmware has no RNG protocol
+pub fn stage_seed(bt: &BootServices) -> Option<&'static [u8]> {
+    let mut seed = [0u8; SEED_LEN];
+    if !fill(bt, &mut seed) {
+        info!("No RNG protocol, the kernel gets no entropy seed");
+        return None;
+    }
+
+    let base = match bt.allocate_pages(AllocateType::AnyPages, crate::memtype::BOOT_INFO, 1) {
+        Ok(base) => base.log() as *mut u8,
+        Err(e) => {
+            warn!(
+                "Unable to allocate a page for the RNG seed: {:?}",
+                e.status()
+            );
+            return None;
+        }
+    };
+    let staged = unsafe {
+        core::ptr::copy_nonoverlapping(seed.as_ptr(), base, SEED_LEN);
+        core::slice::from_raw_parts(base, SEED_LEN)
+    };
+
+    info!("Got {} bytes of entropy from the RNG protocol", SEED_LEN);
+    Some(staged)
+}
@@ -0,0 +1,267 @@
+//! Where the kernel's PT_LOAD segments go in physical memory. Nothing here touches that memory,
+//! `load_kernel_image` does the reserving and copying.
+
+use arrayvec::ArrayVec;
+use goblin::elf::{program_header, Elf, ProgramHeader};
+
+use crate::PAGE_SIZE;
+
+/// The whole pages covering `[addr, addr + size)`, as a page aligned start and end. A segment
+/// that doesn't start on a page boundary still needs the page its first byte is in.
+pub fn page_span(addr: u64, size: u64) -> (u64, u64) {
+    let start = addr & !(PAGE_SIZE - 1);
+    let end = (addr + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    (start, end)
+}
+
+/// The PT_LOAD program headers of `obj`, the only segments that describe memory to populate.
+/// Others like PT_GNU_STACK or PT_NOTE have addresses that mean nothing to the loader.
+pub fn loadable<'a>(obj: &'a Elf) -> impl Iterator<Item = &'a ProgramHeader> {
+    obj.program_headers
+        .iter()
+        .filter(|ph| ph.p_type == program_header::PT_LOAD)
+}
+
+/// The first PT_LOAD segment whose file range doesn't fit in an image of `len` bytes. Copying it
+/// would read past the end of the image, which a truncated or crafted file can ask for.
+pub fn out_of_bounds<'a>(obj: &'a Elf, len: usize) -> Option<&'a ProgramHeader> {
+    loadable(obj).find(|ph| {
+        ph.p_offset
+            .checked_add(ph.p_filesz)
+            .map_or(true, |end| end > len as u64)
+    })
+}
+
+/// The page ranges the PT_LOAD segments of `obj` are copied to once slid by `load_bias`, sorted by
+/// address. Segments that aren't page aligned can share a page, and allocating the same page twice
+/// fails, so overlapping ranges are merged into their union. None if there are more than `N`.
+pub fn segment_pages<const N: usize>(obj: &Elf, load_bias: u64) -> Option<ArrayVec<(u64, u64), N>> {
+    let mut ranges = ArrayVec::<(u64, u64), N>::new();
+    for ph in loadable(obj) {
+        ranges
+            .try_push(page_span(ph.p_paddr + load_bias, ph.p_memsz))
+            .ok()?;
+    }
+    ranges.sort_unstable_by_key(|r| r.0);
+
+    let mut merged = ArrayVec::<(u64, u64), N>::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start < last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(merged)
+}
+
+/// Copy the `p_filesz` bytes of `ph` out of `image` to the start of `dest`, the memory the segment
+/// occupies, and zero the rest of `dest`. Anything past `p_filesz` up to `p_memsz` is .bss.
+pub fn copy_segment(image: &[u8], ph: &ProgramHeader, dest: &mut [u8]) {
+    let src = &image[ph.p_offset as usize..][..ph.p_filesz as usize];
+    let (file, bss) = dest.split_at_mut(src.len().min(dest.len()));
+    file.copy_from_slice(&src[..file.len()]);
+    bss.fill(0);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A program header of a test image, loaded at the same virtual and physical address
+    pub(crate) struct Seg {
+        pub p_type: u32,
+        pub offset: u64,
+        pub addr: u64,
+        pub filesz: u64,
+        pub memsz: u64,
+    }
+
+    impl Seg {
+        pub(crate) fn load(offset: u64, addr: u64, filesz: u64, memsz: u64) -> Seg {
+            Seg {
+                p_type: program_header::PT_LOAD,
+                offset,
+                addr,
+                filesz,
+                memsz,
+            }
+        }
+    }
+
+    /// The byte every test image has at file offset `i`, never zero so copied bytes stand out
+    /// from zeroed ones
+    pub(crate) fn pattern(i: usize) -> u8 {
+        0x80 | (i % 127) as u8
+    }
+
+    /// A `len` byte x86_64 ELF executable with `segs` as its program headers, entered at the
+    /// first one. Everything after the headers is filled with `pattern`.
+    pub(crate) fn image(segs: &[Seg], len: usize) -> Vec<u8> {
+        let mut buf: Vec<u8> = (0..len).map(pattern).collect();
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header.extend_from_slice(&2u16.to_le_bytes()); // e_type, ET_EXEC
+        header.extend_from_slice(&62u16.to_le_bytes()); // e_machine, EM_X86_64
+        header.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        header.extend_from_slice(&segs.first().map_or(0, |s| s.addr).to_le_bytes()); // e_entry
+        header.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        header.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        header.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        header.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        header.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        header.extend_from_slice(&(segs.len() as u16).to_le_bytes()); // e_phnum
+        header.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+        header.extend_from_slice(&0u16.to_le_bytes()); // e_shnum
+        header.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+
+        for s in segs {
+            header.extend_from_slice(&s.p_type.to_le_bytes());
+            header.extend_from_slice(&(program_header::PF_R | program_header::PF_W).to_le_bytes());
+            for field in [s.offset, s.addr, s.addr, s.filesz, s.memsz, PAGE_SIZE] {
+                header.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        buf[..header.len()].copy_from_slice(&header);
+        buf
+    }
+
+    /// Memory from `base` to `base + len` as firmware might leave it, with every PT_LOAD segment
+    /// of `obj` copied in
+    pub(crate) fn load(obj: &Elf, data: &[u8], base: u64, len: usize) -> Vec<u8> {
+        let mut memory = vec![0xEE; len];
+        for ph in loadable(obj) {
+            let start = (ph.p_paddr - base) as usize;
+            copy_segment(data, ph, &mut memory[start..start + ph.p_memsz as usize]);
+        }
+        memory
+    }
+
+    #[test]
+    fn page_span_rounds_out_to_whole_pages() {
+        assert_eq!(page_span(0x20_0000, 0x1000), (0x20_0000, 0x20_1000));
+        assert_eq!(page_span(0x20_0800, 0x1000), (0x20_0000, 0x20_2000));
+        assert_eq!(page_span(0x20_0000, 0), (0x20_0000, 0x20_0000));
+    }
+
+    #[test]
+    fn adjacent_segments_in_one_page_are_reserved_once() {
+        // .text and .data packed together by a linker script that doesn't page align sections
+        let data = image(
+            &[
+                Seg::load(0x1000, 0x20_0000, 0x800, 0x800),
+                Seg::load(0x1800, 0x20_0800, 0x900, 0x900),
+            ],
+            0x3000,
+        );
+        let obj = Elf::parse(&data).unwrap();
+
+        let pages = segment_pages::<4>(&obj, 0).unwrap();
+        assert_eq!(pages.as_slice(), &[(0x20_0000, 0x20_2000)]);
+
+        let slid = segment_pages::<4>(&obj, 0x10_0000).unwrap();
+        assert_eq!(slid.as_slice(), &[(0x30_0000, 0x30_2000)]);
+    }
+
+    #[test]
+    fn separate_segments_stay_separate() {
+        let data = image(
+            &[
+                Seg::load(0x2000, 0x40_0000, 0x1000, 0x1000),
+                Seg::load(0x1000, 0x20_0000, 0x1000, 0x1000),
+            ],
+            0x3000,
+        );
+        let obj = Elf::parse(&data).unwrap();
+
+        let pages = segment_pages::<4>(&obj, 0).unwrap();
+        assert_eq!(
+            pages.as_slice(),
+            &[(0x20_0000, 0x20_1000), (0x40_0000, 0x40_1000)]
+        );
+        assert!(segment_pages::<1>(&obj, 0).is_none());
+    }
+
+    #[test]
+    fn bss_tail_is_zeroed() {
+        let data = image(&[Seg::load(0x1000, 0x20_0000, 0x100, 0x3000)], 0x2000);
+        let obj = Elf::parse(&data).unwrap();
+
+        let memory = load(&obj, &data, 0x20_0000, 0x4000);
+        assert_eq!(&memory[..0x100], &data[0x1000..0x1100]);
+        assert!(memory[0x100..0x3000].iter().all(|&b| b == 0));
+        // nothing past p_memsz is touched
+        assert!(memory[0x3000..].iter().all(|&b| b == 0xEE));
+    }
+
+    #[test]
+    fn gnu_stack_header_is_not_loaded() {
+        // PT_GNU_STACK only carries the stack's permissions, its address is meaningless
+        let stack = Seg {
+            p_type: program_header::PT_GNU_STACK,
+            offset: 0x1000,
+            addr: 0x20_1000,
+            filesz: 0x100,
+            memsz: 0x100,
+        };
+        let data = image(&[Seg::load(0x1000, 0x20_0000, 0x100, 0x100), stack], 0x2000);
+        let obj = Elf::parse(&data).unwrap();
+
+        assert_eq!(loadable(&obj).count(), 1);
+        assert_eq!(
+            segment_pages::<4>(&obj, 0).unwrap().as_slice(),
+            &[(0x20_0000, 0x20_1000)]
+        );
+
+        let memory = load(&obj, &data, 0x20_0000, 0x2000);
+        assert_eq!(&memory[..0x100], &data[0x1000..0x1100]);
+        assert!(memory[0x1000..].iter().all(|&b| b == 0xEE));
+    }
+
+    #[test]
+    fn out_of_range_offset_is_caught() {
+        let data = image(
+            &[
+                Seg::load(0x1000, 0x20_0000, 0x100, 0x100),
+                Seg::load(0x8000, 0x20_1000, 0x100, 0x100),
+            ],
+            0x2000,
+        );
+        let obj = Elf::parse(&data).unwrap();
+
+        let ph = out_of_bounds(&obj, data.len()).unwrap();
+        assert_eq!(ph.p_offset, 0x8000);
+        // it fits once the image is long enough
+        assert!(out_of_bounds(&obj, 0x8100).is_none());
+    }
+
+    #[test]
+    fn overflowing_file_range_is_caught() {
+        let data = image(
+            &[Seg::load(u64::MAX - 0x10, 0x20_0000, 0x100, 0x100)],
+            0x2000,
+        );
+        let obj = Elf::parse(&data).unwrap();
+
+        assert!(out_of_bounds(&obj, data.len()).is_some());
+    }
+
+    #[test]
+    fn unaligned_segment_keeps_its_page_offset() {
+        // starts 0x234 into a page and runs 0x34 bytes into the page after it
+        let data = image(&[Seg::load(0x1234, 0x20_0234, 0x100, 0x1000)], 0x2000);
+        let obj = Elf::parse(&data).unwrap();
+
+        let pages = segment_pages::<4>(&obj, 0).unwrap();
+        assert_eq!(pages.as_slice(), &[(0x20_0000, 0x20_2000)]);
+
+        // the pages are reserved from the rounded down start, the copy goes to p_paddr itself
+        let (start, end) = pages[0];
+        let memory = load(&obj, &data, start, (end - start) as usize);
+        assert!(memory[..0x234].iter().all(|&b| b == 0xEE));
+        assert_eq!(&memory[0x234..0x334], &data[0x1234..0x1334]);
+        assert!(memory[0x334..0x1234].iter().all(|&b| b == 0));
+        assert!(memory[0x1234..].iter().all(|&b| b == 0xEE));
+    }
+}
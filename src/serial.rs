@@ -0,0 +1,98 @@
+//! Minimal polled driver for a 16550 compatible UART.
+//!
+//! Unlike the UEFI console this keeps working after boot services have been exited, which makes it
+//! the only way to see what happens during the handoff to the kernel.
+//!
+//! The UART is accessed through x86 I/O ports, on other architectures writes are dropped.
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::asm;
+use core::fmt;
+
+/// I/O port base of the UART used for boot logging, COM1 on PC compatibles
+pub const SERIAL_PORT_BASE: u16 = 0x3F8;
+
+/// Divisor for 115200 baud from the standard 1.8432 MHz UART clock
+const BAUD_DIVISOR: u16 = 1;
+
+// register offsets from the port base
+const DATA: u16 = 0;
+const INT_ENABLE: u16 = 1;
+const FIFO_CTRL: u16 = 2;
+const LINE_CTRL: u16 = 3;
+const MODEM_CTRL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// Transmitter holding register empty
+const LSR_THR_EMPTY: u8 = 0x20;
+
+#[derive(Clone, Copy)]
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base }
+    }
+
+    /// Program the UART for 115200 8N1 with FIFOs enabled and interrupts off
+    pub fn init(&self) {
+        unsafe {
+            outb(self.base + INT_ENABLE, 0x00);
+            // set DLAB to program the baud rate divisor
+            outb(self.base + LINE_CTRL, 0x80);
+            outb(self.base + DATA, (BAUD_DIVISOR & 0xFF) as u8);
+            outb(self.base + INT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+            // 8 data bits, no parity, one stop bit, DLAB cleared
+            outb(self.base + LINE_CTRL, 0x03);
+            // enable and clear FIFOs, 14 byte threshold
+            outb(self.base + FIFO_CTRL, 0xC7);
+            // DTR + RTS + OUT2
+            outb(self.base + MODEM_CTRL, 0x0B);
+        }
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        unsafe {
+            while inb(self.base + LINE_STATUS) & LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // terminals expect CRLF line endings
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn outb(_port: u16, _value: u8) {}
+
+// reads back as an empty transmit register so write_byte never spins
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn inb(_port: u16) -> u8 {
+    LSR_THR_EMPTY
+}
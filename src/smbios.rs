@@ -0,0 +1,22 @@
+//! Discovery of the SMBIOS entry point structure.
+
+use core::ffi::c_void;
+
+use uefi::table::cfg::{ConfigTableEntry, SMBIOS3_GUID, SMBIOS_GUID};
+
+/// Find the SMBIOS entry point in the UEFI configuration table, preferring the 64-bit SMBIOS 3.0
+/// entry point over the legacy 32-bit one
+pub fn find_entry_point(config_table: &[ConfigTableEntry]) -> Option<*const c_void> {
+    if let Some(entry) = config_table.iter().find(|e| e.guid == SMBIOS3_GUID) {
+        info!("Found SMBIOS 3.0 entry point @ {:#?}", entry.address);
+        return Some(entry.address);
+    }
+
+    if let Some(entry) = config_table.iter().find(|e| e.guid == SMBIOS_GUID) {
+        info!("Found SMBIOS entry point @ {:#?}", entry.address);
+        return Some(entry.address);
+    }
+
+    warn!("No SMBIOS entry point found in the UEFI configuration table");
+    None
+}
@@ -0,0 +1,57 @@
+//! Writer for the legacy VGA text buffer, enabled with the `vga-text` feature.
+//!
+//! Only machines with a CSM or a legacy compatible video card have a text buffer at `0xB8000`,
+//! on anything else the writes go to whatever memory happens to be there. It is used as a last
+//! resort output once the UEFI console is gone, for setups where serial isn't wired up.
+
+use core::fmt;
+
+const BUFFER: *mut u16 = 0xB8000 as *mut u16;
+const COLUMNS: usize = 80;
+const ROWS: usize = 25;
+
+/// Light grey on black
+const ATTRIBUTE: u16 = 0x07 << 8;
+
+/// Index of the next cell written, the buffer scrolls once it reaches the end
+static mut CURSOR: usize = 0;
+
+pub struct VgaText;
+
+impl VgaText {
+    fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            if byte == b'\n' {
+                CURSOR += COLUMNS - CURSOR % COLUMNS;
+            } else {
+                BUFFER.add(CURSOR).write_volatile(ATTRIBUTE | byte as u16);
+                CURSOR += 1;
+            }
+
+            if CURSOR >= COLUMNS * ROWS {
+                scroll();
+                CURSOR = COLUMNS * (ROWS - 1);
+            }
+        }
+    }
+}
+
+impl fmt::Write for VgaText {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // the buffer holds code page 437, anything outside ASCII would be mojibake
+            self.write_byte(if byte.is_ascii() { byte } else { b'?' });
+        }
+        Ok(())
+    }
+}
+
+/// Move every line up by one and blank the last
+unsafe fn scroll() {
+    core::ptr::copy(BUFFER.add(COLUMNS), BUFFER, COLUMNS * (ROWS - 1));
+    for col in 0..COLUMNS {
+        BUFFER
+            .add(COLUMNS * (ROWS - 1) + col)
+            .write_volatile(ATTRIBUTE | b' ' as u16);
+    }
+}